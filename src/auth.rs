@@ -0,0 +1,207 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+
+use crate::db_utils;
+use crate::AppState;
+
+/// Every key starts with this, so leaked keys are easy to search for.
+pub const KEY_PREFIX: &str = "stw_";
+
+/// What an API key may do. `Admin` allows everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    Upload,
+    Read,
+    DownloadJob,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Upload => "upload",
+            Scope::Read => "read",
+            Scope::DownloadJob => "download-job",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "upload" => Ok(Scope::Upload),
+            "read" => Ok(Scope::Read),
+            "download-job" => Ok(Scope::DownloadJob),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("Unknown scope {:?}", other)),
+        }
+    }
+}
+
+/// The key a request authenticated with, stored in the request extensions.
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|s| *s == scope || *s == Scope::Admin)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("API key required")]
+    Missing,
+    #[error("Invalid or revoked API key")]
+    Invalid,
+    #[error("API key lacks the {0} scope")]
+    Forbidden(Scope),
+    #[error("Database error: {0}")]
+    Db(String),
+}
+
+impl actix_web::ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Missing | AuthError::Invalid => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AuthError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut builder = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            builder.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        builder.json(serde_json::json!({ "error": self.to_string() }))
+    }
+}
+
+/// Hex SHA-256 of a key, as stored in the `ApiKey` table.
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Create a key with the given scopes. Returns its ID and the key itself,
+/// which is not stored and can't be shown again.
+pub fn mint_key(conn: &Connection, name: &str, scopes: &[Scope]) -> rusqlite::Result<(i64, String)> {
+    let key = format!("{}{}", KEY_PREFIX, hex(&rand::random::<[u8; 32]>()));
+    let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+    let id = db_utils::insert_api_key(conn, name, &hash_key(&key), &key[..KEY_PREFIX.len() + 8], &scopes)?;
+    Ok((id, key))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The key presented with a request, from `Authorization: Bearer` or `X-API-Key`.
+fn presented_key(req: &ServiceRequest) -> Option<String> {
+    let headers = req.headers();
+    if let Some(value) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        if let Some((scheme, token)) = value.split_once(' ') {
+            if scheme.eq_ignore_ascii_case("bearer") {
+                return Some(token.trim().to_string());
+            }
+        }
+    }
+    headers.get("x-api-key").and_then(|v| v.to_str().ok()).map(|v| v.trim().to_string())
+}
+
+fn auth_enabled(state: Option<&web::Data<AppState>>) -> bool {
+    state.is_none_or(|state| state.config.auth.enabled)
+}
+
+/// Middleware resolving the request's API key, if any, into an `ApiKey` in
+/// the request extensions. A key that is presented but unknown or revoked is
+/// rejected here; whether a route needs a key at all is up to its handler,
+/// which calls `require`.
+pub async fn authenticate<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let state = req.app_data::<web::Data<AppState>>().cloned();
+    if let (true, Some(state), Some(key)) = (auth_enabled(state.as_ref()), state, presented_key(&req)) {
+        match lookup_key(&state, &key) {
+            Ok(key) => {
+                req.extensions_mut().insert(key);
+            }
+            Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+fn lookup_key(state: &AppState, key: &str) -> Result<ApiKey, AuthError> {
+    let conn = state.db_pool.get().map_err(|e| AuthError::Db(e.to_string()))?;
+    let record = db_utils::use_api_key(&conn, &hash_key(key))
+        .map_err(|e| AuthError::Db(e.to_string()))?
+        .ok_or(AuthError::Invalid)?;
+    Ok(ApiKey {
+        id: record.id,
+        name: record.name,
+        // Scopes this build doesn't know are ignored
+        scopes: record.scopes.iter().filter_map(|s| s.parse().ok()).collect(),
+    })
+}
+
+/// Check that the request may use `scope`. Always passes when auth is disabled.
+pub fn require(req: &HttpRequest, scope: Scope) -> Result<(), AuthError> {
+    if !auth_enabled(req.app_data::<web::Data<AppState>>()) {
+        return Ok(());
+    }
+    match req.extensions().get::<ApiKey>() {
+        Some(key) if key.allows(scope) => Ok(()),
+        Some(_) => Err(AuthError::Forbidden(scope)),
+        None => Err(AuthError::Missing),
+    }
+}
+
+/// Who to record as the uploader of a request's files: the API key it
+/// authenticated with, as `key:<id>`. With auth disabled there is no key and
+/// the connecting peer's IP is used instead; `X-Forwarded-For` and
+/// `Forwarded` are set by the client and can't be trusted for this.
+pub fn uploader(req: &HttpRequest) -> Option<String> {
+    if let Some(key) = req.extensions().get::<ApiKey>() {
+        return Some(format!("key:{}", key.id));
+    }
+    if auth_enabled(req.app_data::<web::Data<AppState>>()) {
+        return None;
+    }
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_allows_every_scope() {
+        let key = ApiKey { id: 1, name: "ci".into(), scopes: vec![Scope::Upload] };
+        assert!(key.allows(Scope::Upload));
+        assert!(!key.allows(Scope::Read));
+        let admin = ApiKey { scopes: vec![Scope::Admin], ..key };
+        assert!([Scope::Upload, Scope::Read, Scope::DownloadJob, Scope::Admin].iter().all(|s| admin.allows(*s)));
+        assert_eq!("download-job".parse::<Scope>(), Ok(Scope::DownloadJob));
+        assert!("write".parse::<Scope>().is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Checksums clients send with an upload, verified against the bytes that
+//! were stored, and the digest headers sent back with downloads.
+//!
+//! Accepted on uploads: `Content-MD5` (RFC 1864), `Digest` (RFC 3230),
+//! `Repr-Digest` (RFC 9530) and `X-Checksum-Sha256`, in hex or base64.
+//! Algorithms other than SHA-256 and MD5 are ignored.
+
+use actix_web::http::header::HeaderMap;
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+pub const CONTENT_MD5: &str = "content-md5";
+pub const DIGEST: &str = "digest";
+pub const REPR_DIGEST: &str = "repr-digest";
+pub const X_CHECKSUM_SHA256: &str = "x-checksum-sha256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "md5" => Some(Algorithm::Md5),
+            "sha-256" => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha256 => 32,
+        }
+    }
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        })
+    }
+}
+
+/// A digest the client expects the uploaded content to have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub value: Vec<u8>,
+    pub header: &'static str, // where it came from, for error messages
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChecksumError {
+    #[error("Malformed {0} header")]
+    Malformed(&'static str),
+    #[error("Content does not match the {algorithm} checksum in {header}")]
+    Mismatch { algorithm: Algorithm, header: &'static str },
+}
+
+impl actix_web::ResponseError for ChecksumError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            ChecksumError::Malformed(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            ChecksumError::Mismatch { .. } => actix_web::http::StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+/// The checksums in a request's (or multipart part's) headers.
+pub fn from_headers(headers: &HeaderMap) -> Result<Vec<Checksum>, ChecksumError> {
+    let mut checksums = Vec::new();
+    for value in headers.get_all(CONTENT_MD5) {
+        let value = value.to_str().map_err(|_| ChecksumError::Malformed(CONTENT_MD5))?;
+        checksums.push(decode(Algorithm::Md5, value.trim(), CONTENT_MD5)?);
+    }
+    // `sha-256=<base64>, md5=<base64>`
+    for value in headers.get_all(DIGEST) {
+        let value = value.to_str().map_err(|_| ChecksumError::Malformed(DIGEST))?;
+        for member in value.split(',').filter(|m| !m.trim().is_empty()) {
+            let (name, digest) = member.split_once('=').ok_or(ChecksumError::Malformed(DIGEST))?;
+            if let Some(algorithm) = Algorithm::from_name(name) {
+                checksums.push(decode(algorithm, digest.trim(), DIGEST)?);
+            }
+        }
+    }
+    // A structured field dictionary of byte sequences: `sha-256=:<base64>:`
+    for value in headers.get_all(REPR_DIGEST) {
+        let value = value.to_str().map_err(|_| ChecksumError::Malformed(REPR_DIGEST))?;
+        for member in value.split(',').filter(|m| !m.trim().is_empty()) {
+            let (name, digest) = member.split_once('=').ok_or(ChecksumError::Malformed(REPR_DIGEST))?;
+            let digest = digest.split(';').next().unwrap_or("").trim();
+            let digest = digest.strip_prefix(':').and_then(|d| d.strip_suffix(':'))
+                .ok_or(ChecksumError::Malformed(REPR_DIGEST))?;
+            if let Some(algorithm) = Algorithm::from_name(name) {
+                checksums.push(decode(algorithm, digest, REPR_DIGEST)?);
+            }
+        }
+    }
+    for value in headers.get_all(X_CHECKSUM_SHA256) {
+        let value = value.to_str().map_err(|_| ChecksumError::Malformed(X_CHECKSUM_SHA256))?.trim();
+        let digest = match from_hex(value) {
+            Some(digest) => Checksum { algorithm: Algorithm::Sha256, value: digest, header: X_CHECKSUM_SHA256 },
+            None => decode(Algorithm::Sha256, value, X_CHECKSUM_SHA256)?,
+        };
+        if digest.value.len() != Algorithm::Sha256.len() {
+            return Err(ChecksumError::Malformed(X_CHECKSUM_SHA256));
+        }
+        checksums.push(digest);
+    }
+    Ok(checksums)
+}
+
+fn decode(algorithm: Algorithm, base64: &str, header: &'static str) -> Result<Checksum, ChecksumError> {
+    let value = base64::engine::general_purpose::STANDARD.decode(base64)
+        .map_err(|_| ChecksumError::Malformed(header))?;
+    if value.len() != algorithm.len() {
+        return Err(ChecksumError::Malformed(header));
+    }
+    Ok(Checksum { algorithm, value, header })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+/// Hashes content as it is read: always SHA-256, which files are stored
+/// under, and MD5 only if a checksum needs it.
+#[derive(Debug)]
+pub struct Hasher {
+    sha256: Sha256,
+    md5: Option<Md5>,
+}
+
+impl Hasher {
+    pub fn new(checksums: &[Checksum]) -> Self {
+        Hasher {
+            sha256: Sha256::new(),
+            md5: checksums.iter().any(|c| c.algorithm == Algorithm::Md5).then(Md5::new),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+    }
+
+    pub fn finish(self) -> Digests {
+        Digests {
+            sha256: self.sha256.finalize().to_vec(),
+            md5: self.md5.map(|md5| md5.finalize().to_vec()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Digests {
+    pub sha256: Vec<u8>,
+    pub md5: Option<Vec<u8>>,
+}
+
+impl Digests {
+    pub fn sha256_hex(&self) -> String {
+        self.sha256.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Check the content against every checksum the client sent.
+    pub fn verify(&self, checksums: &[Checksum]) -> Result<(), ChecksumError> {
+        for checksum in checksums {
+            let actual = match checksum.algorithm {
+                Algorithm::Sha256 => Some(&self.sha256),
+                Algorithm::Md5 => self.md5.as_ref(),
+            };
+            if actual != Some(&checksum.value) {
+                return Err(ChecksumError::Mismatch { algorithm: checksum.algorithm, header: checksum.header });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `Repr-Digest` and `Digest` headers for a stored file, from its hex SHA-256.
+pub fn digest_headers(sha256_hex: &str) -> Vec<(&'static str, String)> {
+    let Some(sha256) = from_hex(sha256_hex) else {
+        return Vec::new();
+    };
+    let encoded = base64::engine::general_purpose::STANDARD.encode(sha256);
+    vec![
+        (REPR_DIGEST, format!("sha-256=:{}:", encoded)),
+        (DIGEST, format!("sha-256={}", encoded)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        // "hello world"
+        let sha256_hex = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let sha256_b64 = "uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=";
+        let md5_b64 = "XrY7u+Ae7tCTyyK7j1rNww==";
+        let checksums = from_headers(&headers(&[
+            (CONTENT_MD5, md5_b64),
+            (DIGEST, &format!("SHA-256={}, unixsum=30637", sha256_b64)),
+            (REPR_DIGEST, &format!("sha-512=:AAAA:, sha-256=:{}:", sha256_b64)),
+            (X_CHECKSUM_SHA256, sha256_hex),
+        ])).unwrap();
+        assert_eq!(checksums.len(), 4);
+
+        let mut hasher = Hasher::new(&checksums);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let digests = hasher.finish();
+        assert_eq!(digests.sha256_hex(), sha256_hex);
+        assert!(digests.verify(&checksums).is_ok());
+
+        let mut hasher = Hasher::new(&checksums);
+        hasher.update(b"hello there");
+        assert!(matches!(
+            hasher.finish().verify(&checksums),
+            Err(ChecksumError::Mismatch { algorithm: Algorithm::Md5, header: CONTENT_MD5 })
+        ));
+
+        for (name, value) in [(CONTENT_MD5, "not base64"), (REPR_DIGEST, "sha-256=abc"), (X_CHECKSUM_SHA256, "abcd")] {
+            assert!(matches!(from_headers(&headers(&[(name, value)])), Err(ChecksumError::Malformed(_))), "{}", name);
+        }
+        assert_eq!(digest_headers(sha256_hex)[0].1, format!("sha-256=:{}:", sha256_b64));
+    }
+}
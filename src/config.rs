@@ -1,19 +1,491 @@
-use std::path::PathBuf; 
-
-#[derive(Debug, Clone)] 
-pub struct Config { 
-    pub media_path: PathBuf, 
-    pub max_file_size: u64, 
-    pub allowed_mime_types: Vec<String>, 
-}
-
-impl Default for Config { 
-    fn default() -> Self { 
-        Self { 
-            media_path: std::env::current_dir().unwrap().join("media"), 
-            max_file_size: 100 * 1024 * 1024, 
-            allowed_mime_types: vec![ 
-                "audio/*".into(), 
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// File consulted when `STOWAGE_CONFIG` is not set.
+pub const DEFAULT_CONFIG_FILE: &str = "stowage.toml";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("Failed to parse config file {path}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("Invalid value {value:?} for {var}: {reason}")]
+    Env { var: String, value: String, reason: String },
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+}
+
+/// How failed `/download` jobs are retried.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Total attempts per job, including the first one.
+    pub max_attempts: u32,
+    pub initial_delay_secs: f64,
+    pub max_delay_secs: f64,
+    pub multiplier: f64,
+    /// Fraction of the delay randomly added or removed, 0 to 1.
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// Delay before attempt `attempt + 1`, given that `attempt` (1-based) just
+    /// failed. `random` is a number in `[0, 1)` used for jitter.
+    pub fn delay_after(&self, attempt: u32, random: f64) -> std::time::Duration {
+        let exponent = attempt.saturating_sub(1).min(64) as i32;
+        let base = (self.initial_delay_secs * self.multiplier.powi(exponent)).min(self.max_delay_secs);
+        let jittered = base * (1.0 + self.jitter * (2.0 * random - 1.0));
+        std::time::Duration::from_secs_f64(jittered.clamp(0.0, self.max_delay_secs))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay_secs: 2.0,
+            max_delay_secs: 300.0,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+/// Who may call the API.
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Require an API key with the right scope on every route except
+    /// `/about` and files marked public. Keys are minted with `stowage keys`.
+    /// Off unless configured, so servers set up before keys existed keep
+    /// working until they have minted some.
+    pub enabled: bool,
+}
+
+/// A secret for signing file URLs, named so signatures say which one they used.
+#[derive(Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String,
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey").field("id", &self.id).field("secret", &"<redacted>").finish()
+    }
+}
+
+/// Signed, expiring links to files.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignedUrlConfig {
+    /// The first key signs new URLs; all of them are accepted, so a new key
+    /// can be put first while URLs signed with the old one run out.
+    pub keys: Vec<SigningKey>,
+    pub default_ttl_secs: u64,
+    pub max_ttl_secs: u64,
+}
+
+impl Default for SignedUrlConfig {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            default_ttl_secs: 3600,
+            max_ttl_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// Which remote URLs `/download` jobs may fetch, and how.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FetchConfig {
+    pub allowed_schemes: Vec<String>,
+    /// Hosts that may be fetched, as names or `*.example.com`. Empty allows
+    /// every host not denied.
+    pub allowed_hosts: Vec<String>,
+    pub denied_hosts: Vec<String>,
+    /// Allow loopback, private, link-local and other non-public addresses.
+    pub allow_private_ips: bool,
+    pub max_redirects: usize,
+    pub connect_timeout_secs: u64,
+    /// Longest wait for the response headers or the next chunk of the body
+    pub read_timeout_secs: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: vec!["http".into(), "https".into()],
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            allow_private_ips: false,
+            max_redirects: 5,
+            connect_timeout_secs: 10,
+            read_timeout_secs: 30,
+        }
+    }
+}
+
+/// A CORS policy. `"*"` in a list allows anything.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsPolicy {
+    /// Origins such as `https://app.example.com`
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts may read besides the CORS-safelisted ones
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<usize>,
+    /// Reject requests from other origins with 400 instead of just leaving
+    /// out the CORS headers. Browsers send simple requests such as form
+    /// uploads without a preflight, so only this stops them from running.
+    pub block_other_origins: bool,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        let list = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        Self {
+            allowed_origins: list(&["*"]),
+            allowed_methods: list(&["*"]),
+            allowed_headers: list(&["*"]),
+            exposed_headers: list(&[
+                "Accept-Ranges", "Content-Disposition", "Content-Length", "Content-Range", "ETag", "Location",
+                "Repr-Digest", "Digest",
+                // tus resumable uploads
+                "Tus-Resumable", "Tus-Version", "Tus-Extension", "Tus-Max-Size",
+                "Upload-Offset", "Upload-Length", "Upload-Metadata", "Upload-Expires",
+            ]),
+            allow_credentials: false,
+            max_age_secs: Some(3600),
+            block_other_origins: false,
+        }
+    }
+}
+
+/// A CORS policy for the routes under `path`, optionally only for some
+/// methods. Settings it leaves out come from `[cors.default]`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsRoute {
+    /// Path prefix, matched by whole segments: `/files` covers `/files/abc`
+    pub path: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub allowed_headers: Option<Vec<String>>,
+    pub exposed_headers: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age_secs: Option<usize>,
+    pub block_other_origins: Option<bool>,
+}
+
+impl CorsRoute {
+    /// This route's policy, filled in from `base`
+    pub fn policy(&self, base: &CorsPolicy) -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: self.allowed_origins.clone().unwrap_or_else(|| base.allowed_origins.clone()),
+            allowed_methods: self.allowed_methods.clone().unwrap_or_else(|| base.allowed_methods.clone()),
+            allowed_headers: self.allowed_headers.clone().unwrap_or_else(|| base.allowed_headers.clone()),
+            exposed_headers: self.exposed_headers.clone().unwrap_or_else(|| base.exposed_headers.clone()),
+            allow_credentials: self.allow_credentials.unwrap_or(base.allow_credentials),
+            max_age_secs: self.max_age_secs.or(base.max_age_secs),
+            block_other_origins: self.block_other_origins.unwrap_or(base.block_other_origins),
+        }
+    }
+}
+
+/// CORS for the API: a default policy plus per-route overrides. The most
+/// specific matching route wins.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub default: CorsPolicy,
+    pub routes: Vec<CorsRoute>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub media_path: PathBuf,
+    pub db_path: PathBuf,
+    pub max_concurrent_downloads: usize,
+    pub max_file_size: u64,
+    /// Per-MIME overrides of `max_file_size`, keyed by exact type
+    /// (`application/json`) or category (`video/*`).
+    pub max_file_size_by_type: HashMap<String, u64>,
+    /// Types accepted for storage, as exact types or `type/*` categories.
+    pub allowed_mime_types: Vec<String>,
+    pub retry: RetryPolicy,
+    /// How long a running job's lease lasts without a heartbeat before the
+    /// job is considered abandoned and requeued.
+    pub job_lease_secs: u64,
+    /// How long a resumable upload may go without receiving data before it
+    /// and its partial data are removed.
+    pub upload_expiry_secs: u64,
+    pub auth: AuthConfig,
+    pub signed_urls: SignedUrlConfig,
+    pub cors: CorsConfig,
+    pub fetch: FetchConfig,
+}
+
+impl Config {
+    /// Load the configuration for the server: defaults, overlaid by the TOML
+    /// file named in `STOWAGE_CONFIG` (or `stowage.toml` if present), overlaid
+    /// by individual environment variables, then validated.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var("STOWAGE_CONFIG") {
+            Ok(path) => Self::from_file(path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(DEFAULT_CONFIG_FILE)?,
+            Err(_) => Self::default(),
+        };
+        config.apply_env(|var| std::env::var(var).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse a TOML config file. Missing keys keep their defaults.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::Read { path: path.to_path_buf(), source })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })
+    }
+
+    /// Override settings from environment variables, looked up through `var`.
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(name: &str, value: String) -> Result<T, ConfigError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value.trim().parse().map_err(|e: T::Err| ConfigError::Env {
+                var: name.to_string(),
+                value,
+                reason: e.to_string(),
+            })
+        }
+
+        if let Some(value) = var("HOST") {
+            self.host = value;
+        }
+        if let Some(value) = var("PORT") {
+            self.port = parse("PORT", value)?;
+        }
+        if let Some(value) = var("MEDIA_PATH") {
+            self.media_path = PathBuf::from(value);
+        }
+        if let Some(value) = var("DB_PATH") {
+            self.db_path = PathBuf::from(value);
+        }
+        if let Some(value) = var("MAX_CONCURRENT_DOWNLOADS") {
+            self.max_concurrent_downloads = parse("MAX_CONCURRENT_DOWNLOADS", value)?;
+        }
+        if let Some(value) = var("MAX_FILE_SIZE") {
+            self.max_file_size = parse("MAX_FILE_SIZE", value)?;
+        }
+        if let Some(value) = var("JOB_LEASE_SECS") {
+            self.job_lease_secs = parse("JOB_LEASE_SECS", value)?;
+        }
+        if let Some(value) = var("UPLOAD_EXPIRY_SECS") {
+            self.upload_expiry_secs = parse("UPLOAD_EXPIRY_SECS", value)?;
+        }
+        if let Some(value) = var("RETRY_MAX_ATTEMPTS") {
+            self.retry.max_attempts = parse("RETRY_MAX_ATTEMPTS", value)?;
+        }
+        if let Some(value) = var("AUTH_ENABLED") {
+            self.auth.enabled = parse("AUTH_ENABLED", value)?;
+        }
+        if let Some(value) = var("SIGNED_URL_KEYS") {
+            self.signed_urls.keys = value.split(',')
+                .map(str::trim)
+                .filter(|k| !k.is_empty())
+                .map(|k| match k.split_once(':') {
+                    Some((id, secret)) => Ok(SigningKey { id: id.trim().to_string(), secret: secret.to_string() }),
+                    None => Err(ConfigError::Env {
+                        var: "SIGNED_URL_KEYS".into(),
+                        value: "<redacted>".into(),
+                        reason: "expected comma-separated id:secret pairs".into(),
+                    }),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(value) = var("CORS_ALLOWED_ORIGINS") {
+            self.cors.default.allowed_origins = value.split(',')
+                .map(|o| o.trim().to_string())
+                .filter(|o| !o.is_empty())
+                .collect();
+        }
+        if let Some(value) = var("FETCH_ALLOW_PRIVATE_IPS") {
+            self.fetch.allow_private_ips = parse("FETCH_ALLOW_PRIVATE_IPS", value)?;
+        }
+        if let Some(value) = var("ALLOWED_MIME_TYPES") {
+            self.allowed_mime_types = value.split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Reject settings the server can't run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.host.trim().is_empty() {
+            return Err(ConfigError::Invalid("host must not be empty".into()));
+        }
+        if self.max_concurrent_downloads == 0 {
+            return Err(ConfigError::Invalid("max_concurrent_downloads must be at least 1".into()));
+        }
+        if self.max_file_size == 0 {
+            return Err(ConfigError::Invalid("max_file_size must be greater than 0".into()));
+        }
+        for (pattern, limit) in &self.max_file_size_by_type {
+            if !is_valid_mime_pattern(pattern) {
+                return Err(ConfigError::Invalid(format!("max_file_size_by_type has invalid MIME type {:?}", pattern)));
+            }
+            if *limit == 0 {
+                return Err(ConfigError::Invalid(format!("max_file_size_by_type limit for {} must be greater than 0", pattern)));
+            }
+        }
+        if self.allowed_mime_types.is_empty() {
+            return Err(ConfigError::Invalid("allowed_mime_types must not be empty".into()));
+        }
+        if let Some(pattern) = self.allowed_mime_types.iter().find(|p| !is_valid_mime_pattern(p)) {
+            return Err(ConfigError::Invalid(format!("allowed_mime_types has invalid MIME type {:?}", pattern)));
+        }
+        if self.job_lease_secs < 3 {
+            return Err(ConfigError::Invalid("job_lease_secs must be at least 3".into()));
+        }
+        if self.upload_expiry_secs == 0 {
+            return Err(ConfigError::Invalid("upload_expiry_secs must be at least 1".into()));
+        }
+        let signed = &self.signed_urls;
+        for (i, key) in signed.keys.iter().enumerate() {
+            if key.id.is_empty() || !key.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err(ConfigError::Invalid(format!("signed_urls key id {:?} must be non-empty letters, digits, - or _", key.id)));
+            }
+            if key.secret.len() < 32 {
+                return Err(ConfigError::Invalid(format!("signed_urls key {} must have a secret of at least 32 bytes", key.id)));
+            }
+            if signed.keys[..i].iter().any(|k| k.id == key.id) {
+                return Err(ConfigError::Invalid(format!("signed_urls key id {} is used twice", key.id)));
+            }
+        }
+        if signed.default_ttl_secs == 0 || signed.default_ttl_secs > signed.max_ttl_secs {
+            return Err(ConfigError::Invalid("signed_urls ttls must satisfy 0 < default_ttl_secs <= max_ttl_secs".into()));
+        }
+        let fetch = &self.fetch;
+        if fetch.allowed_schemes.is_empty() {
+            return Err(ConfigError::Invalid("fetch.allowed_schemes must not be empty".into()));
+        }
+        if let Some(scheme) = fetch.allowed_schemes.iter().find(|s| !matches!(s.as_str(), "http" | "https")) {
+            return Err(ConfigError::Invalid(format!("fetch.allowed_schemes has unsupported scheme {:?}", scheme)));
+        }
+        if let Some(host) = fetch.allowed_hosts.iter().chain(&fetch.denied_hosts).find(|h| h.trim_start_matches("*.").is_empty()) {
+            return Err(ConfigError::Invalid(format!("fetch host pattern {:?} is empty", host)));
+        }
+        if fetch.connect_timeout_secs == 0 || fetch.read_timeout_secs == 0 {
+            return Err(ConfigError::Invalid("fetch timeouts must be at least 1 second".into()));
+        }
+        validate_cors_policy("cors", &self.cors.default)?;
+        for route in &self.cors.routes {
+            if !route.path.starts_with('/') {
+                return Err(ConfigError::Invalid(format!("cors route path {:?} must start with /", route.path)));
+            }
+            if let Some(method) = route.methods.iter().find(|m| m.parse::<actix_web::http::Method>().is_err()) {
+                return Err(ConfigError::Invalid(format!("cors route {} has invalid method {:?}", route.path, method)));
+            }
+            validate_cors_policy(&format!("cors route {}", route.path), &route.policy(&self.cors.default))?;
+        }
+        let retry = &self.retry;
+        if retry.max_attempts == 0 {
+            return Err(ConfigError::Invalid("retry.max_attempts must be at least 1".into()));
+        }
+        if !(retry.initial_delay_secs >= 0.0 && retry.max_delay_secs >= retry.initial_delay_secs) {
+            return Err(ConfigError::Invalid("retry delays must satisfy 0 <= initial_delay_secs <= max_delay_secs".into()));
+        }
+        if !(retry.multiplier >= 1.0 && retry.multiplier.is_finite()) {
+            return Err(ConfigError::Invalid("retry.multiplier must be at least 1".into()));
+        }
+        if !(0.0..=1.0).contains(&retry.jitter) {
+            return Err(ConfigError::Invalid("retry.jitter must be between 0 and 1".into()));
+        }
+        Ok(())
+    }
+
+    /// Size limit for a file of the given MIME type. Exact matches win over
+    /// category matches, which win over `max_file_size`.
+    pub fn max_size_for(&self, mime_type: &str) -> u64 {
+        let mime_type = mime_type.split(';').next().unwrap_or("").trim();
+        if let Some(limit) = self.max_file_size_by_type.get(mime_type) {
+            return *limit;
+        }
+        let category = format!("{}/*", mime_type.split('/').next().unwrap_or(""));
+        self.max_file_size_by_type.get(&category).copied().unwrap_or(self.max_file_size)
+    }
+
+    /// The largest size any file may have, used before its type is known.
+    pub fn max_size_ceiling(&self) -> u64 {
+        self.max_file_size_by_type.values().copied().fold(self.max_file_size, u64::max)
+    }
+}
+
+fn validate_cors_policy(name: &str, policy: &CorsPolicy) -> Result<(), ConfigError> {
+    let invalid = |what: &str, value: &str| Err(ConfigError::Invalid(format!("{} has invalid {} {:?}", name, what, value)));
+    for origin in policy.allowed_origins.iter().filter(|o| *o != "*") {
+        match origin.parse::<actix_web::http::Uri>() {
+            Ok(uri) if uri.scheme().is_some() && uri.host().is_some() && matches!(uri.path(), "" | "/") && !origin.ends_with('/') => {}
+            _ => return invalid("origin", origin),
+        }
+    }
+    for method in policy.allowed_methods.iter().filter(|m| *m != "*") {
+        if method.parse::<actix_web::http::Method>().is_err() {
+            return invalid("method", method);
+        }
+    }
+    for header in policy.allowed_headers.iter().chain(&policy.exposed_headers).filter(|h| *h != "*") {
+        if actix_web::http::header::HeaderName::try_from(header.as_str()).is_err() {
+            return invalid("header", header);
+        }
+    }
+    // Letting every site make credentialed requests defeats the point of CORS
+    if policy.allow_credentials && policy.allowed_origins.iter().any(|o| o == "*") {
+        return Err(ConfigError::Invalid(format!("{} can't allow credentials for every origin", name)));
+    }
+    Ok(())
+}
+
+/// `type/subtype` or `type/*`.
+fn is_valid_mime_pattern(pattern: &str) -> bool {
+    match pattern.split_once('/') {
+        Some((top, sub)) => !top.is_empty() && top != "*" && !sub.is_empty() && !sub.contains('/'),
+        None => false,
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".into(),
+            port: 8080,
+            media_path: PathBuf::from("./media"),
+            db_path: PathBuf::from("stowage.db"),
+            max_concurrent_downloads: 5,
+            max_file_size: 100 * 1024 * 1024,
+            max_file_size_by_type: HashMap::from([
+                ("video/*".to_string(), 1024 * 1024 * 1024),
+                ("audio/*".to_string(), 512 * 1024 * 1024),
+                ("application/json".to_string(), 10 * 1024 * 1024),
+            ]),
+            allowed_mime_types: vec![
+                "audio/*".into(),
                 "video/*".into(),
                 "image/*".into(),
                 "application/octet-stream".into(),
@@ -22,6 +494,123 @@ impl Default for Config {
                 "application/rss+xml".into(),
                 "application/xml".into(),
             ],
+            retry: RetryPolicy::default(),
+            job_lease_secs: 60,
+            upload_expiry_secs: 86400,
+            auth: AuthConfig::default(),
+            signed_urls: SignedUrlConfig::default(),
+            cors: CorsConfig::default(),
+            fetch: FetchConfig::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_file_then_env_overrides() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "port = 9000\nmax_file_size = 1024\nallowed_mime_types = [\"image/*\"]").unwrap();
+        let mut config = Config::from_file(file.path()).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.allowed_mime_types, vec!["image/*"]);
+
+        config.apply_env(|var| match var {
+            "PORT" => Some("9100".into()),
+            "ALLOWED_MIME_TYPES" => Some("image/*, application/json".into()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.max_file_size, 1024);
+        assert_eq!(config.allowed_mime_types, vec!["image/*", "application/json"]);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_settings_are_reported() {
+        let mut config = Config::default();
+        let err = config.apply_env(|var| (var == "PORT").then(|| "eighty".into())).unwrap_err();
+        assert!(err.to_string().contains("PORT"));
+
+        config.allowed_mime_types = vec!["video".into()];
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let config = Config {
+            fetch: FetchConfig { allowed_schemes: vec!["file".into()], ..Default::default() },
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "max_file_sise = 10").unwrap();
+        assert!(matches!(Config::from_file(file.path()), Err(ConfigError::Parse { .. })));
+    }
+
+    #[test]
+    fn test_retry_delay_grows_and_is_capped() {
+        let policy = RetryPolicy { jitter: 0.0, ..Default::default() };
+        assert_eq!(policy.delay_after(1, 0.5).as_secs_f64(), 2.0);
+        assert_eq!(policy.delay_after(3, 0.5).as_secs_f64(), 8.0);
+        assert_eq!(policy.delay_after(20, 0.5).as_secs_f64(), 300.0);
+
+        let policy = RetryPolicy::default();
+        let low = policy.delay_after(2, 0.0).as_secs_f64();
+        let high = policy.delay_after(2, 0.999).as_secs_f64();
+        assert!((3.2..4.0).contains(&low) && (4.0..4.8).contains(&high), "{} {}", low, high);
+    }
+
+    #[test]
+    fn test_signing_keys_from_env() {
+        let mut config = Config::default();
+        config.apply_env(|var| (var == "SIGNED_URL_KEYS").then(|| "new:0123456789abcdef0123456789abcdef, old:fedcba9876543210fedcba9876543210".into())).unwrap();
+        assert_eq!(config.signed_urls.keys.iter().map(|k| k.id.as_str()).collect::<Vec<_>>(), ["new", "old"]);
+        assert!(config.validate().is_ok());
+        assert!(!format!("{:?}", config).contains("0123456789abcdef"), "secrets are not logged");
+
+        config.signed_urls.keys[1].id = "new".into();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.signed_urls.keys[1].secret = "short".into();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        assert!(config.apply_env(|var| (var == "SIGNED_URL_KEYS").then(|| "nocolon".into())).is_err());
+    }
+
+    #[test]
+    fn test_cors_routes_inherit_default_policy() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, r#"
+[cors.default]
+allowed_origins = ["*"]
+max_age_secs = 600
+
+[[cors.routes]]
+path = "/upload"
+allowed_origins = ["https://app.example.com"]
+allow_credentials = true
+"#).unwrap();
+        let config = Config::from_file(file.path()).unwrap();
+        assert!(config.validate().is_ok());
+        let policy = config.cors.routes[0].policy(&config.cors.default);
+        assert_eq!(policy.allowed_origins, vec!["https://app.example.com"]);
+        assert_eq!(policy.max_age_secs, Some(600));
+        assert!(policy.exposed_headers.iter().any(|h| h == "ETag"));
+
+        let mut config = config;
+        config.cors.routes[0].allowed_origins = None;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))), "credentials for any origin");
+        config.cors.routes[0].allowed_origins = Some(vec!["app.example.com".into()]);
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))), "origin without a scheme");
+    }
+
+    #[test]
+    fn test_max_size_for_prefers_exact_type() {
+        let config = Config::default();
+        assert_eq!(config.max_size_for("application/json; charset=utf-8"), 10 * 1024 * 1024);
+        assert_eq!(config.max_size_for("video/mp4"), 1024 * 1024 * 1024);
+        assert_eq!(config.max_size_for("image/png"), config.max_file_size);
+        assert_eq!(config.max_size_ceiling(), 1024 * 1024 * 1024);
+    }
+}
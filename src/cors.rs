@@ -0,0 +1,115 @@
+//! CORS policies from `Config::cors`, applied per route.
+
+use actix_cors::Cors;
+use actix_web::dev::RequestHead;
+use actix_web::http::header;
+
+use crate::config::{CorsConfig, CorsPolicy, CorsRoute};
+
+/// The actix-cors middleware for a policy. The policy must have passed
+/// `Config::validate`.
+pub fn build(policy: &CorsPolicy) -> Cors {
+    let any = |list: &[String]| list.iter().any(|item| item == "*");
+    let mut cors = Cors::default().max_age(policy.max_age_secs);
+    if any(&policy.allowed_origins) {
+        cors = cors.allow_any_origin();
+    } else {
+        for origin in &policy.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+    cors = if any(&policy.allowed_methods) {
+        cors.allow_any_method()
+    } else {
+        cors.allowed_methods(policy.allowed_methods.iter().map(String::as_str))
+    };
+    cors = if any(&policy.allowed_headers) {
+        cors.allow_any_header()
+    } else {
+        cors.allowed_headers(policy.allowed_headers.iter().map(String::as_str))
+    };
+    cors = if any(&policy.exposed_headers) {
+        cors.expose_any_header()
+    } else {
+        cors.expose_headers(policy.exposed_headers.iter().map(String::as_str))
+    };
+    if policy.allow_credentials {
+        cors = cors.supports_credentials();
+    }
+    cors.block_on_origin_mismatch(policy.block_other_origins)
+}
+
+/// Route policies in the order they should be tried: longer paths first,
+/// and for equal paths those limited to some methods first.
+pub fn ordered_routes(config: &CorsConfig) -> Vec<&CorsRoute> {
+    let mut routes: Vec<&CorsRoute> = config.routes.iter().collect();
+    routes.sort_by_key(|route| (std::cmp::Reverse(route.path.trim_end_matches('/').len()), route.methods.is_empty()));
+    routes
+}
+
+/// Whether a route policy applies to a request. A preflight is matched by the
+/// method it asks about, so it gets the same policy as the request it precedes.
+pub fn route_matches(route: &CorsRoute, head: &RequestHead) -> bool {
+    let prefix = route.path.trim_end_matches('/');
+    let path = head.uri.path();
+    let path_matches = prefix.is_empty()
+        || path == prefix
+        || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'));
+    if !path_matches {
+        return false;
+    }
+    if route.methods.is_empty() {
+        return true;
+    }
+    let preflight_method = head.headers.get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| head.method == actix_web::http::Method::OPTIONS);
+    let method = preflight_method.unwrap_or(head.method.as_str());
+    route.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn route(path: &str, methods: &[&str]) -> CorsRoute {
+        CorsRoute {
+            path: path.into(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            allowed_origins: None,
+            allowed_methods: None,
+            allowed_headers: None,
+            exposed_headers: None,
+            allow_credentials: None,
+            max_age_secs: None,
+            block_other_origins: None,
+        }
+    }
+
+    #[test]
+    fn test_route_matching() {
+        let files = route("/files/", &[]);
+        let head = |method: &str, uri: &str| TestRequest::default()
+            .method(method.parse().unwrap())
+            .uri(uri)
+            .to_srv_request();
+        assert!(route_matches(&files, head("GET", "/files").head()));
+        assert!(route_matches(&files, head("GET", "/files/abc/meta").head()));
+        assert!(!route_matches(&files, head("GET", "/filesystem").head()));
+
+        let upload = route("/files", &["PUT", "post"]);
+        assert!(route_matches(&upload, head("POST", "/files").head()));
+        assert!(!route_matches(&upload, head("GET", "/files").head()));
+        let preflight = TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/files/abc")
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "PUT"))
+            .to_srv_request();
+        assert!(route_matches(&upload, preflight.head()));
+
+        let config = CorsConfig { routes: vec![route("/", &[]), files, upload], ..Default::default() };
+        let order: Vec<(&str, usize)> = ordered_routes(&config).iter().map(|r| (r.path.as_str(), r.methods.len())).collect();
+        assert_eq!(order, [("/files", 2), ("/files/", 0), ("/", 0)]);
+    }
+}
@@ -8,16 +8,33 @@ pub enum JobStatus {
     NotStarted,
     Running,
     Completed,
+    Failed,
+    Cancelled,
+    Retrying,
 }
 
-impl ToString for JobStatus {
-    fn to_string(&self) -> String {
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
         match self {
-            JobStatus::NotStarted => "NotStarted".to_string(),
-            JobStatus::Running => "Running".to_string(),
-            JobStatus::Completed => "Completed".to_string(),
+            JobStatus::NotStarted => "NotStarted",
+            JobStatus::Running => "Running",
+            JobStatus::Completed => "Completed",
+            JobStatus::Failed => "Failed",
+            JobStatus::Cancelled => "Cancelled",
+            JobStatus::Retrying => "Retrying",
         }
     }
+
+    /// Whether the job has reached a state it will never leave
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled)
+    }
+}
+
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl std::str::FromStr for JobStatus {
@@ -27,6 +44,9 @@ impl std::str::FromStr for JobStatus {
             "NotStarted" => Ok(JobStatus::NotStarted),
             "Running" => Ok(JobStatus::Running),
             "Completed" => Ok(JobStatus::Completed),
+            "Failed" => Ok(JobStatus::Failed),
+            "Cancelled" => Ok(JobStatus::Cancelled),
+            "Retrying" => Ok(JobStatus::Retrying),
             _ => Err(()),
         }
     }
@@ -39,32 +59,270 @@ pub struct JobRecord {
     pub file_id: Option<i64>,
     pub download_url: String,
     pub error: Option<String>,
+    pub attempts: i64, // attempts started so far
+    pub max_attempts: i64,
+    pub next_attempt_at: Option<String>, // when a Retrying job becomes eligible again
+    pub lease_expires_at: Option<String>, // when a Running job is presumed abandoned
 }
 
+/// One try at running a job, as shown in its attempt log
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct JobAttemptRecord {
+    pub attempt: i64,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A logical file: one upload or download, with its own ID and metadata.
+/// The bytes live in a `Blob` that identical files share.
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct FileRecord {
     pub id: i64,
-    pub filepath: String,
+    pub uuid: String, // public file ID
+    pub blob_id: i64,
+    pub filepath: String, // storage key of the blob
     pub url: String,
     pub hash: String,
+    pub original_filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub extension: Option<String>,
+    pub size: Option<i64>, // bytes
+    pub uploader: Option<String>,
+    pub created_at: Option<String>,
+    pub ref_count: i64, // files sharing the blob, this one included
+    pub deduplicated: bool, // the bytes were already stored when this file arrived
+    pub source_url: Option<String>, // where a worker download fetched it from
+    pub media_info: Option<String>, // JSON object of properties read from the content
+    pub public: bool, // readable without an API key
+    pub expires_at: Option<String>, // when the file is removed
+}
+
+/// Everything needed to insert a row into the File table
+#[derive(Debug, Clone, Default)]
+pub struct NewFile {
+    pub uuid: String,
+    pub blob_id: i64,
+    pub url: String,
+    pub original_filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub extension: Option<String>,
+    pub uploader: Option<String>,
+    pub tags: Vec<String>,
+    pub deduplicated: bool,
+    pub source_url: Option<String>,
+    pub media_info: Option<String>, // JSON
+    pub public: bool,
+    pub expires_at: Option<String>, // "YYYY-MM-DD HH:MM:SS" UTC
+}
+
+/// An API key, without the key itself: only its hash is stored
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ApiKeyRecord {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String, // first characters of the key, to tell keys apart
+    pub scopes: Vec<String>,
+    pub created_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+}
+
+/// A resumable upload in progress. Once complete it becomes the file with
+/// the same ID.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct UploadRecord {
+    pub id: String, // UUID
+    pub length: i64, // total bytes, from Upload-Length
+    pub received: i64, // bytes stored so far
+    pub metadata: Option<String>, // Upload-Metadata as sent
+    pub uploader: Option<String>,
+    pub created_at: Option<String>,
+    pub expires_at: String,
+    pub completed_at: Option<String>,
+    pub expired: bool,
+}
+
+/// Stored bytes, shared by every file with the same content
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BlobRecord {
+    pub id: i64,
+    pub hash: String,
+    pub filepath: String, // storage key
+    pub size: i64,
+    pub ref_count: i64,
 }
 
 pub fn init_db(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS File (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
+            uuid TEXT, -- public file ID
             filepath TEXT NOT NULL,
             url TEXT NOT NULL,
-            hash TEXT NOT NULL
+            hash TEXT NOT NULL,
+            original_filename TEXT,
+            mime_type TEXT,
+            extension TEXT,
+            size INTEGER, -- bytes
+            uploader TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            blob_id INTEGER REFERENCES Blob(id),
+            deduplicated INTEGER NOT NULL DEFAULT 0,
+            source_url TEXT,
+            media_info TEXT, -- JSON
+            public INTEGER NOT NULL DEFAULT 0,
+            expires_at TIMESTAMP -- removed after this, if set
+        )",
+        [],
+    )?;
+
+    // Add metadata columns if they don't exist (for existing databases).
+    // SQLite can't add a column with a CURRENT_TIMESTAMP default, so
+    // insert_file sets created_at explicitly.
+    for column in [
+        "original_filename TEXT",
+        "mime_type TEXT",
+        "extension TEXT",
+        "size INTEGER",
+        "uploader TEXT",
+        "created_at TIMESTAMP",
+        "blob_id INTEGER REFERENCES Blob(id)",
+        "deduplicated INTEGER NOT NULL DEFAULT 0",
+        "source_url TEXT",
+        "media_info TEXT",
+        "public INTEGER NOT NULL DEFAULT 0",
+        "expires_at TIMESTAMP",
+    ] {
+        let _ = conn.execute(&format!("ALTER TABLE File ADD COLUMN {}", column), []);
+    }
+
+    // Add uuid column if it doesn't exist (for existing databases), then
+    // backfill it from the file stem, which is where the public ID used to live
+    let _ = conn.execute(
+        "ALTER TABLE File ADD COLUMN uuid TEXT",
+        [],
+    );
+    backfill_file_uuids(conn)?;
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_file_uuid ON File(uuid)",
+        [],
+    )?;
+    let _ = conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_file_hash ON File(hash)",
+        [],
+    );
+
+    // Create Blob table. File rows used to own their bytes; now identical
+    // files point at one shared blob, which is removed with its last file.
+    // The legacy filepath/hash/size columns on File mirror the blob.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS Blob (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hash TEXT NOT NULL, -- hex SHA-256
+            filepath TEXT NOT NULL, -- storage key
+            size INTEGER NOT NULL, -- bytes
+            ref_count INTEGER NOT NULL DEFAULT 0, -- File rows using this blob
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+    backfill_blobs(conn)?;
+    // One blob per content, so concurrent uploads of the same bytes can't
+    // both store them; older databases may hold duplicates to merge first
+    for key in merge_duplicate_blobs(conn)? {
+        log::warn!("Merged a duplicate blob; {} is no longer used and can be deleted", key);
+    }
+    let _ = conn.execute("DROP INDEX IF EXISTS idx_blob_hash", []);
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_blob_hash_unique ON Blob(hash)",
+        [],
+    )?;
+    let _ = conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_file_blob ON File(blob_id)",
+        [],
+    );
+
+    // Indexes for listing files: one per sort order, plus the MIME filter
+    for index in [
+        "idx_file_created ON File(COALESCE(created_at, ''), id)",
+        "idx_file_size ON File(COALESCE(size, 0), id)",
+        "idx_file_name ON File(COALESCE(original_filename, '') COLLATE NOCASE, id)",
+        "idx_file_mime ON File(mime_type)",
+        "idx_file_expires ON File(expires_at) WHERE expires_at IS NOT NULL",
+    ] {
+        let _ = conn.execute(&format!("CREATE INDEX IF NOT EXISTS {}", index), []);
+    }
+
+    // Create FileTag table
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS FileTag (
+            file_id INTEGER NOT NULL REFERENCES File(id) ON DELETE CASCADE,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (file_id, tag)
         )",
         [],
     )?;
+    let _ = conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_file_tag ON FileTag(tag)",
+        [],
+    );
+
+    // IDs of deleted files stay reserved, so an ID can never be given to
+    // different content, which caches and ETags rely on
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS DeletedFile (
+            uuid TEXT PRIMARY KEY,
+            deleted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS file_id_not_deleted BEFORE INSERT ON File
+         WHEN EXISTS (SELECT 1 FROM DeletedFile WHERE uuid = NEW.uuid)
+         BEGIN SELECT RAISE(ABORT, 'File ID was deleted'); END",
+        [],
+    )?;
+
+    // Create ApiKey table. Keys are random, so a plain SHA-256 is enough
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS ApiKey (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            key_hash TEXT NOT NULL UNIQUE, -- hex SHA-256 of the key
+            prefix TEXT NOT NULL,
+            scopes TEXT NOT NULL, -- comma-separated
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            last_used_at TIMESTAMP,
+            revoked_at TIMESTAMP
+        )",
+        [],
+    )?;
+
+    // Create Upload table for resumable uploads
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS Upload (
+            id TEXT PRIMARY KEY, -- UUID, also the ID of the finished file
+            length INTEGER NOT NULL, -- bytes
+            received INTEGER NOT NULL DEFAULT 0, -- bytes stored so far
+            metadata TEXT, -- Upload-Metadata header as sent
+            uploader TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            expires_at TIMESTAMP NOT NULL,
+            completed_at TIMESTAMP
+        )",
+        [],
+    )?;
+    let _ = conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_upload_expires ON Upload(expires_at)",
+        [],
+    );
 
     // Create Job table
     conn.execute(
         "CREATE TABLE IF NOT EXISTS Job (
             id TEXT PRIMARY KEY, -- UUID as string
-            status TEXT NOT NULL, -- 'NotStarted', 'Running', 'Completed', 'Failed'
+            status TEXT NOT NULL, -- 'NotStarted', 'Running', 'Completed', 'Failed', 'Cancelled', 'Retrying'
             file_id INTEGER,
             download_url TEXT NOT NULL,
             error TEXT, -- Error message if the job failed
@@ -91,20 +349,48 @@ pub fn init_db(conn: &Connection) -> Result<()> {
         [],
     );
     
+    // Add retry columns if they don't exist
+    for column in [
+        "attempts INTEGER NOT NULL DEFAULT 0",
+        "max_attempts INTEGER NOT NULL DEFAULT 1",
+        "next_attempt_at TIMESTAMP",
+        "lease_expires_at TIMESTAMP",
+    ] {
+        let _ = conn.execute(&format!("ALTER TABLE Job ADD COLUMN {}", column), []);
+    }
+    
     // Create index on status for faster lookups
     let _ = conn.execute(
         "CREATE INDEX IF NOT EXISTS idx_job_status ON Job(status)",
         [],
     );
+
+    // Create JobAttempt table, one row per try at running a job
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS JobAttempt (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            job_id TEXT NOT NULL,
+            attempt INTEGER NOT NULL,
+            started_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            finished_at TIMESTAMP,
+            error TEXT, -- NULL if the attempt succeeded or is still running
+            FOREIGN KEY(job_id) REFERENCES Job(id)
+        )",
+        [],
+    )?;
+    let _ = conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_job_attempt_job ON JobAttempt(job_id)",
+        [],
+    );
     Ok(())
 }
 
 
 /// Insert a new Job into the Job table
-pub fn insert_job(conn: &Connection, id: &str, status: &JobStatus, file_id: Option<i64>, download_url: &str) -> Result<()> {
+pub fn insert_job(conn: &Connection, id: &str, status: &JobStatus, file_id: Option<i64>, download_url: &str, max_attempts: u32) -> Result<()> {
     conn.execute(
-        "INSERT INTO Job (id, status, file_id, download_url, error) VALUES (?1, ?2, ?3, ?4, NULL)",
-        params![id, &status.to_string(), file_id, download_url],
+        "INSERT INTO Job (id, status, file_id, download_url, error, max_attempts) VALUES (?1, ?2, ?3, ?4, NULL, ?5)",
+        params![id, &status.to_string(), file_id, download_url, max_attempts],
     )?;
     Ok(())
 }
@@ -112,7 +398,7 @@ pub fn insert_job(conn: &Connection, id: &str, status: &JobStatus, file_id: Opti
 /// Get a Job by UUID
 pub fn get_job_by_id(conn: &Connection, id: &str) -> Result<Option<JobRecord>> {
     let mut stmt = conn.prepare(
-        "SELECT id, status, file_id, download_url, error FROM Job WHERE id = ?1",
+        "SELECT id, status, file_id, download_url, error, attempts, max_attempts, next_attempt_at, lease_expires_at FROM Job WHERE id = ?1",
     )?;
     let mut rows = stmt.query_map([id], |row| {
         let status_str: String = row.get(1)?;
@@ -124,28 +410,64 @@ pub fn get_job_by_id(conn: &Connection, id: &str) -> Result<Option<JobRecord>> {
             file_id: row.get(2)?,
             download_url: row.get(3)?,
             error: row.get(4)?,
+            attempts: row.get(5)?,
+            max_attempts: row.get(6)?,
+            next_attempt_at: row.get(7)?,
+            lease_expires_at: row.get(8)?,
         })
     })?;
     
     rows.next().transpose()
 }
 
-/// Get and start a job that's not started yet
-pub fn get_and_start_job(conn: &Connection) -> Result<Option<JobRecord>> {
-    // Atomically select and update a NotStarted job to Running
+/// The attempt log of a job, oldest first
+pub fn get_job_attempts(conn: &Connection, job_id: &str) -> Result<Vec<JobAttemptRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT attempt, started_at, finished_at, error FROM JobAttempt WHERE job_id = ?1 ORDER BY attempt ASC",
+    )?;
+    let rows = stmt.query_map([job_id], |row| {
+        Ok(JobAttemptRecord {
+            attempt: row.get(0)?,
+            started_at: row.get(1)?,
+            finished_at: row.get(2)?,
+            error: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// SQLite datetime modifier for `duration` from now
+fn offset(duration: std::time::Duration) -> String {
+    format!("+{:.3} seconds", duration.as_secs_f64())
+}
+
+/// Get and start a job that's not started yet, or a retrying job whose
+/// backoff has elapsed. The job is leased to the caller for `lease`, which
+/// must be kept alive with `renew_job_lease`.
+pub fn get_and_start_job(conn: &Connection, lease: std::time::Duration) -> Result<Option<JobRecord>> {
+    // Atomically select and update the job to Running
     let tx = conn.unchecked_transaction()?;
     let job_id: Option<String> = tx.query_row(
-        "SELECT id FROM Job WHERE status = 'NotStarted' ORDER BY created_at ASC LIMIT 1",
+        "SELECT id FROM Job
+         WHERE status IN ('NotStarted', 'Retrying')
+           AND (next_attempt_at IS NULL OR next_attempt_at <= CURRENT_TIMESTAMP)
+         ORDER BY created_at ASC LIMIT 1",
         [],
         |row| row.get(0),
     ).optional()?;
 
     if let Some(job_id) = job_id {
         let updated = tx.execute(
-            "UPDATE Job SET status = 'Running', updated_at = CURRENT_TIMESTAMP WHERE id = ?1 AND status = 'NotStarted'",
-            params![&job_id],
+            "UPDATE Job SET status = 'Running', attempts = attempts + 1, next_attempt_at = NULL,
+                lease_expires_at = datetime('now', ?2), updated_at = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND status IN ('NotStarted', 'Retrying')",
+            params![&job_id, offset(lease)],
         )?;
         if updated == 1 {
+            tx.execute(
+                "INSERT INTO JobAttempt (job_id, attempt, started_at) SELECT id, attempts, CURRENT_TIMESTAMP FROM Job WHERE id = ?1",
+                params![&job_id],
+            )?;
             tx.commit()?;
             if let Some(mut job) = get_job_by_id(conn, &job_id)? {
                 job.status = JobStatus::Running;
@@ -160,68 +482,697 @@ pub fn get_and_start_job(conn: &Connection) -> Result<Option<JobRecord>> {
     Ok(None)
 }
 
-/// Mark a job as completed with the resulting file ID
-pub fn complete_job(conn: &Connection, job_id: &str, file_id: i64) -> Result<()> {
+/// Mark a running job as completed with the resulting file ID.
+/// Returns false if the job was no longer running, e.g. it was cancelled.
+pub fn complete_job(conn: &Connection, job_id: &str, file_id: i64) -> Result<bool> {
     let tx = conn.unchecked_transaction()?;
     
     // Update the job status and file ID
-    tx.execute(
-        "UPDATE Job SET status = ?1, file_id = ?2, updated_at = CURRENT_TIMESTAMP WHERE id = ?3",
+    let updated = tx.execute(
+        "UPDATE Job SET status = ?1, file_id = ?2, lease_expires_at = NULL, updated_at = CURRENT_TIMESTAMP WHERE id = ?3 AND status = 'Running'",
         params!["Completed", file_id, job_id],
     )?;
+    if updated == 1 {
+        finish_attempt(&tx, job_id, None)?;
+    }
     
     tx.commit()?;
-    Ok(())
+    Ok(updated == 1)
 }
 
-/// Mark a job as failed with an error message
-pub fn fail_job(conn: &Connection, job_id: &str, error: &str) -> Result<()> {
+/// Mark a running job as failed with an error message.
+/// Returns false if the job was no longer running, e.g. it was cancelled.
+pub fn fail_job(conn: &Connection, job_id: &str, error: &str) -> Result<bool> {
     let tx = conn.unchecked_transaction()?;
     
     // Update the job status and error message
-    tx.execute(
-        "UPDATE Job SET status = 'Failed', error = ?1, updated_at = CURRENT_TIMESTAMP WHERE id = ?2",
+    let updated = tx.execute(
+        "UPDATE Job SET status = 'Failed', error = ?1, lease_expires_at = NULL, updated_at = CURRENT_TIMESTAMP WHERE id = ?2 AND status = 'Running'",
         params![error, job_id],
     )?;
+    if updated == 1 {
+        finish_attempt(&tx, job_id, Some(error))?;
+    }
     
     tx.commit()?;
+    Ok(updated == 1)
+}
+
+/// Put a running job back in the queue after a failed attempt; it becomes
+/// eligible again once `delay` has passed.
+/// Returns false if the job was no longer running, e.g. it was cancelled.
+pub fn retry_job(conn: &Connection, job_id: &str, error: &str, delay: std::time::Duration) -> Result<bool> {
+    let tx = conn.unchecked_transaction()?;
+    let updated = tx.execute(
+        "UPDATE Job SET status = 'Retrying', error = ?1, next_attempt_at = datetime('now', ?2), lease_expires_at = NULL, updated_at = CURRENT_TIMESTAMP
+         WHERE id = ?3 AND status = 'Running'",
+        params![error, offset(delay), job_id],
+    )?;
+    if updated == 1 {
+        finish_attempt(&tx, job_id, Some(error))?;
+    }
+    tx.commit()?;
+    Ok(updated == 1)
+}
+
+/// Extend the lease on a running job. Returns false if the job is no longer
+/// running, in which case the caller should stop working on it.
+pub fn renew_job_lease(conn: &Connection, job_id: &str, lease: std::time::Duration) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE Job SET lease_expires_at = datetime('now', ?1) WHERE id = ?2 AND status = 'Running'",
+        params![offset(lease), job_id],
+    )?;
+    Ok(updated == 1)
+}
+
+/// Requeue running jobs whose worker has gone away: those whose lease has
+/// expired, or every running job if `all` is set (at startup, when no worker
+/// can be running yet). A job that has used up its attempts fails instead.
+/// Returns the IDs of the affected jobs so their temp files can be removed.
+pub fn recover_stale_jobs(conn: &Connection, all: bool) -> Result<Vec<String>> {
+    const LOST: &str = "Worker stopped while the job was running";
+    let tx = conn.unchecked_transaction()?;
+    let stale = {
+        let mut stmt = tx.prepare(
+            "SELECT id FROM Job WHERE status = 'Running'
+               AND (?1 OR lease_expires_at IS NULL OR lease_expires_at <= CURRENT_TIMESTAMP)",
+        )?;
+        let rows = stmt.query_map(params![all], |row| row.get::<_, String>(0))?;
+        rows.collect::<Result<Vec<_>>>()?
+    };
+    for job_id in &stale {
+        tx.execute(
+            "UPDATE Job SET
+                status = CASE WHEN attempts < max_attempts THEN 'Retrying' ELSE 'Failed' END,
+                error = ?1, lease_expires_at = NULL, next_attempt_at = NULL, updated_at = CURRENT_TIMESTAMP
+             WHERE id = ?2",
+            params![LOST, job_id],
+        )?;
+        finish_attempt(&tx, job_id, Some(LOST))?;
+    }
+    tx.commit()?;
+    Ok(stale)
+}
+
+/// Close the open attempt of a job, if any, with its outcome
+fn finish_attempt(conn: &Connection, job_id: &str, error: Option<&str>) -> Result<()> {
+    conn.execute(
+        "UPDATE JobAttempt SET finished_at = CURRENT_TIMESTAMP, error = ?1 WHERE job_id = ?2 AND finished_at IS NULL",
+        params![error, job_id],
+    )?;
     Ok(())
 }
 
-/// Get file path by hash
-pub fn get_filepath_by_hash(conn: &Connection, hash: &str) -> Result<Option<String>> {
-    let mut stmt = conn.prepare("SELECT filepath FROM File WHERE hash = ?1 LIMIT 1")?;
-    let mut rows = stmt.query_map([hash], |row| row.get(0))?;    
-    rows.next().transpose().map_err(Into::into)
+/// Cancel a job that hasn't finished yet. Returns the job's status from
+/// before the attempt, so a finished status means nothing was changed, or
+/// `None` if there is no such job.
+pub fn cancel_job(conn: &Connection, job_id: &str) -> Result<Option<JobStatus>> {
+    let tx = conn.unchecked_transaction()?;
+    let status: Option<String> = tx.query_row(
+        "SELECT status FROM Job WHERE id = ?1",
+        params![job_id],
+        |row| row.get(0),
+    ).optional()?;
+    let status = status.map(|s| s.parse().unwrap_or(JobStatus::NotStarted));
+    if matches!(status, Some(status) if !status.is_finished()) {
+        tx.execute(
+            "UPDATE Job SET status = 'Cancelled', next_attempt_at = NULL, lease_expires_at = NULL, updated_at = CURRENT_TIMESTAMP WHERE id = ?1",
+            params![job_id],
+        )?;
+        finish_attempt(&tx, job_id, Some("Cancelled"))?;
+    }
+    tx.commit()?;
+    Ok(status)
+}
+
+fn backfill_file_uuids(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id, filepath FROM File WHERE uuid IS NULL")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>>>()?;
+    for (id, filepath) in rows {
+        let uuid = std::path::Path::new(&filepath)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| id.to_string());
+        conn.execute("UPDATE File SET uuid = ?1 WHERE id = ?2", params![uuid, id])?;
+    }
+    Ok(())
 }
 
-/// Get file ID by path
+/// Give every file from before blobs existed a blob of its own, or the blob
+/// of an earlier file with the same hash
+fn backfill_blobs(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id, hash, filepath, size FROM File WHERE blob_id IS NULL ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, Option<i64>>(3)?))
+    })?.collect::<Result<Vec<_>>>()?;
+    for (id, hash, filepath, size) in rows {
+        let blob_id = match get_blob_by_hash(conn, &hash)? {
+            Some(blob) => blob.id,
+            None => insert_blob(conn, &hash, &filepath, size.unwrap_or(0))?,
+        };
+        conn.execute("UPDATE File SET blob_id = ?1 WHERE id = ?2", params![blob_id, id])?;
+        conn.execute("UPDATE Blob SET ref_count = ref_count + 1 WHERE id = ?1", [blob_id])?;
+    }
+    Ok(())
+}
+
+/// Point the files of every blob that repeats another's content at the
+/// oldest blob with that content and delete the rest. Returns the storage
+/// keys of the deleted blobs, whose objects nothing uses any more.
+fn merge_duplicate_blobs(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT b.id, b.filepath, keep.id, keep.filepath FROM Blob b
+         JOIN Blob keep ON keep.hash = b.hash
+         WHERE keep.id = (SELECT MIN(id) FROM Blob WHERE hash = b.hash) AND b.id != keep.id",
+    )?;
+    let duplicates = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?, row.get::<_, String>(3)?))
+    })?.collect::<Result<Vec<_>>>()?;
+    let mut unused = Vec::new();
+    for (id, filepath, keep_id, keep_filepath) in duplicates {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute("UPDATE File SET blob_id = ?1, filepath = ?2 WHERE blob_id = ?3", params![keep_id, keep_filepath, id])?;
+        tx.execute(
+            "UPDATE Blob SET ref_count = ref_count + (SELECT ref_count FROM Blob WHERE id = ?2) WHERE id = ?1",
+            params![keep_id, id],
+        )?;
+        tx.execute("DELETE FROM Blob WHERE id = ?1", [id])?;
+        tx.commit()?;
+        if filepath != keep_filepath {
+            unused.push(filepath);
+        }
+    }
+    Ok(unused)
+}
+
+/// Rewrite absolute file paths stored before the storage backend existed into
+/// keys relative to `root`
+pub fn relativize_filepaths(conn: &Connection, root: &str) -> Result<usize> {
+    let prefix = format!("{}/", root.trim_end_matches('/'));
+    let mut updated = 0;
+    for table in ["File", "Blob"] {
+        updated += conn.execute(
+            &format!("UPDATE {} SET filepath = substr(filepath, length(?1) + 1) WHERE substr(filepath, 1, length(?1)) = ?1", table),
+            params![prefix],
+        )?;
+    }
+    Ok(updated)
+}
+
+fn file_from_row(row: &rusqlite::Row) -> Result<FileRecord> {
+    Ok(FileRecord {
+        id: row.get(0)?,
+        uuid: row.get(1)?,
+        blob_id: row.get(2)?,
+        filepath: row.get(3)?,
+        url: row.get(4)?,
+        hash: row.get(5)?,
+        original_filename: row.get(6)?,
+        mime_type: row.get(7)?,
+        extension: row.get(8)?,
+        size: row.get(9)?,
+        uploader: row.get(10)?,
+        created_at: row.get(11)?,
+        ref_count: row.get(12)?,
+        deduplicated: row.get(13)?,
+        source_url: row.get(14)?,
+        media_info: row.get(15)?,
+        public: row.get(16)?,
+        expires_at: row.get(17)?,
+    })
+}
+
+const FILE_SELECT: &str = "SELECT f.id, f.uuid, f.blob_id, b.filepath, f.url, b.hash, f.original_filename,
+    f.mime_type, f.extension, b.size, f.uploader, f.created_at, b.ref_count, f.deduplicated,
+    f.source_url, f.media_info, f.public, f.expires_at
+    FROM File f JOIN Blob b ON b.id = f.blob_id";
+
+// Expired files are gone as far as readers are concerned, even before the
+// worker gets round to deleting them
+const UNEXPIRED: &str = "(f.expires_at IS NULL OR f.expires_at > CURRENT_TIMESTAMP)";
+
+/// Get a file by its public UUID, unless it has expired
+pub fn get_file_by_uuid(conn: &Connection, uuid: &str) -> Result<Option<FileRecord>> {
+    conn.query_row(
+        &format!("{} WHERE f.uuid = ?1 AND {}", FILE_SELECT, UNEXPIRED),
+        [uuid],
+        file_from_row,
+    ).optional()
+}
+
+/// An unexpired file with content `hash`, preferring a public one
+pub fn get_file_by_hash(conn: &Connection, hash: &str) -> Result<Option<FileRecord>> {
+    conn.query_row(
+        &format!("{} WHERE b.hash = ?1 AND {} ORDER BY f.public DESC, f.id LIMIT 1", FILE_SELECT, UNEXPIRED),
+        [hash],
+        file_from_row,
+    ).optional()
+}
+
+/// Get a file by its numeric ID
 pub fn get_file_by_id(conn: &Connection, id: i64) -> Result<FileRecord> {
     conn.query_row(
-        "SELECT id, filepath, url, hash FROM File WHERE id = ?1",
+        &format!("{} WHERE f.id = ?1", FILE_SELECT),
         [id],
-        |row| Ok(FileRecord {
-            id: row.get(0)?,
-            filepath: row.get(1)?,
-            url: row.get(2)?,
-            hash: row.get(3)?,
-        }),
+        file_from_row,
     )
 }
 
-pub fn get_file_id_by_path(conn: &Connection, path: &str) -> Result<i64> {
+/// Order of a file listing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileSort {
+    #[default]
+    CreatedAt,
+    Size,
+    Filename,
+}
+
+impl FileSort {
+    // Must match the expressions of the listing indexes in init_db
+    fn expr(&self) -> &'static str {
+        match self {
+            FileSort::CreatedAt => "COALESCE(f.created_at, '')",
+            FileSort::Size => "COALESCE(f.size, 0)",
+            FileSort::Filename => "COALESCE(f.original_filename, '') COLLATE NOCASE",
+        }
+    }
+}
+
+/// Sort value of the last file on a page
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum SortKey {
+    Int(i64),
+    Text(String),
+}
+
+/// Position after which the next page of a listing starts
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileCursor {
+    pub sort: FileSort,
+    pub descending: bool,
+    pub key: SortKey,
+    pub id: i64,
+}
+
+impl FileCursor {
+    fn after(sort: FileSort, descending: bool, file: &FileRecord) -> Self {
+        let key = match sort {
+            FileSort::CreatedAt => SortKey::Text(file.created_at.clone().unwrap_or_default()),
+            FileSort::Size => SortKey::Int(file.size.unwrap_or(0)),
+            FileSort::Filename => SortKey::Text(file.original_filename.clone().unwrap_or_default()),
+        };
+        FileCursor { sort, descending, key, id: file.id }
+    }
+}
+
+/// Filters and order for `list_files`. Sizes are in bytes; dates are SQLite
+/// timestamps (`YYYY-MM-DD HH:MM:SS`, UTC) and both ends are inclusive.
+#[derive(Debug, Clone, Default)]
+pub struct FileQuery {
+    pub mime: Option<String>, // a category like `image`, or a full type like `image/png`
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    pub filename: Option<String>, // case-insensitive substring of the original filename
+    pub tag: Option<String>,
+    pub sort: FileSort,
+    pub descending: bool,
+    pub limit: usize,
+    pub cursor: Option<FileCursor>, // must have the same sort and direction
+}
+
+/// One page of a file listing
+pub struct FilePage {
+    pub files: Vec<FileRecord>,
+    pub next_cursor: Option<FileCursor>, // None on the last page
+}
+
+/// List files matching `query`, a page at a time
+pub fn list_files(conn: &Connection, query: &FileQuery) -> Result<FilePage> {
+    use rusqlite::types::Value;
+    // Placeholders are bound in the order the conditions are added
+    let mut conditions: Vec<String> = vec![UNEXPIRED.to_string()];
+    let mut values: Vec<Value> = Vec::new();
+    let mut push = |condition: String, value: Vec<Value>| {
+        conditions.push(condition);
+        values.extend(value);
+    };
+
+    if let Some(mime) = &query.mime {
+        let mime = mime.to_ascii_lowercase();
+        match mime.strip_suffix("/*").or(if mime.contains('/') { None } else { Some(mime.as_str()) }) {
+            // A range rather than LIKE so the index on mime_type is used
+            // '0' is the character after '/'
+            Some(category) => push("f.mime_type >= ? AND f.mime_type < ?".into(), vec![
+                Value::Text(format!("{}/", category)),
+                Value::Text(format!("{}0", category)),
+            ]),
+            None => push("f.mime_type = ?".into(), vec![Value::Text(mime)]),
+        }
+    }
+    if let Some(min) = query.min_size {
+        push("COALESCE(f.size, 0) >= ?".into(), vec![Value::Integer(min)]);
+    }
+    if let Some(max) = query.max_size {
+        push("COALESCE(f.size, 0) <= ?".into(), vec![Value::Integer(max)]);
+    }
+    if let Some(after) = &query.created_after {
+        push("f.created_at >= ?".into(), vec![Value::Text(after.clone())]);
+    }
+    if let Some(before) = &query.created_before {
+        push("f.created_at <= ?".into(), vec![Value::Text(before.clone())]);
+    }
+    if let Some(filename) = &query.filename {
+        push("instr(lower(f.original_filename), lower(?)) > 0".into(), vec![Value::Text(filename.clone())]);
+    }
+    if let Some(tag) = &query.tag {
+        push("EXISTS (SELECT 1 FROM FileTag t WHERE t.file_id = f.id AND t.tag = ?)".into(), vec![Value::Text(tag.clone())]);
+    }
+    let direction = if query.descending { "DESC" } else { "ASC" };
+    if let Some(cursor) = &query.cursor {
+        let key = match &cursor.key {
+            SortKey::Int(i) => Value::Integer(*i),
+            SortKey::Text(s) => Value::Text(s.clone()),
+        };
+        let op = if query.descending { "<" } else { ">" };
+        push(format!("({}, f.id) {} (?, ?)", query.sort.expr(), op), vec![key, Value::Integer(cursor.id)]);
+    }
+
+    let mut sql = FILE_SELECT.to_string();
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    sql.push_str(&format!(
+        " ORDER BY {} {}, f.id {} LIMIT {}",
+        query.sort.expr(), direction, direction, query.limit + 1
+    ));
+
+    let mut stmt = conn.prepare(&sql)?;
+    let mut files = stmt.query_map(rusqlite::params_from_iter(values), file_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    let next_cursor = if files.len() > query.limit {
+        files.truncate(query.limit);
+        files.last().map(|file| FileCursor::after(query.sort, query.descending, file))
+    } else {
+        None
+    };
+    Ok(FilePage { files, next_cursor })
+}
+
+/// Tags of a file, sorted
+pub fn get_file_tags(conn: &Connection, file_id: i64) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT tag FROM FileTag WHERE file_id = ?1 ORDER BY tag")?;
+    let rows = stmt.query_map([file_id], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Get the blob holding content with the given hash
+pub fn get_blob_by_hash(conn: &Connection, hash: &str) -> Result<Option<BlobRecord>> {
     conn.query_row(
-        "SELECT id FROM File WHERE filepath = ?1",
-        [path],
+        "SELECT id, hash, filepath, size, ref_count FROM Blob WHERE hash = ?1 ORDER BY id LIMIT 1",
+        [hash],
+        |row| Ok(BlobRecord {
+            id: row.get(0)?,
+            hash: row.get(1)?,
+            filepath: row.get(2)?,
+            size: row.get(3)?,
+            ref_count: row.get(4)?,
+        }),
+    ).optional()
+}
+
+/// Record newly stored bytes and return the blob ID. The blob has no
+/// references until a file is inserted for it.
+pub fn insert_blob(conn: &Connection, hash: &str, filepath: &str, size: i64) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO Blob (hash, filepath, size, ref_count, created_at) VALUES (?1, ?2, ?3, 0, CURRENT_TIMESTAMP)",
+        params![hash, filepath, size],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Record new content under `filepath`, or find the blob that already holds
+/// the same content. It's one statement, so of two writers with the same
+/// content exactly one inserts and the other gets the winner's blob. Returns
+/// the blob and whether it was inserted, in which case the caller has to
+/// put the object at `filepath` before committing.
+pub fn insert_or_get_blob(conn: &Connection, hash: &str, filepath: &str, size: i64) -> Result<(BlobRecord, bool)> {
+    let inserted: Option<i64> = conn.query_row(
+        "INSERT INTO Blob (hash, filepath, size, ref_count, created_at) VALUES (?1, ?2, ?3, 0, CURRENT_TIMESTAMP)
+         ON CONFLICT(hash) DO NOTHING RETURNING id",
+        params![hash, filepath, size],
         |row| row.get(0),
-    )
+    ).optional()?;
+    match inserted {
+        Some(id) => Ok((BlobRecord { id, hash: hash.to_string(), filepath: filepath.to_string(), size, ref_count: 0 }, true)),
+        None => get_blob_by_hash(conn, hash)?.map(|blob| (blob, false)).ok_or(rusqlite::Error::QueryReturnedNoRows),
+    }
+}
+
+/// Insert a file record referencing an existing blob and return its ID.
+/// Fails with `QueryReturnedNoRows` if the blob no longer exists. Works on
+/// its own or as part of the caller's transaction.
+pub fn insert_file(conn: &Connection, file: &NewFile) -> Result<i64> {
+    conn.execute_batch("SAVEPOINT insert_file")?;
+    match insert_file_rows(conn, file) {
+        Ok(file_id) => {
+            conn.execute_batch("RELEASE insert_file")?;
+            Ok(file_id)
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK TO insert_file; RELEASE insert_file");
+            Err(e)
+        }
+    }
+}
+
+fn insert_file_rows(conn: &Connection, file: &NewFile) -> Result<i64> {
+    let inserted = conn.execute(
+        "INSERT INTO File (uuid, blob_id, filepath, url, hash, original_filename, mime_type, extension, size, uploader, deduplicated, source_url, media_info, public, expires_at, created_at)
+         SELECT ?1, id, filepath, ?2, hash, ?3, ?4, ?5, size, ?6, ?7, ?8, ?9, ?10, ?11, CURRENT_TIMESTAMP FROM Blob WHERE id = ?12",
+        params![
+            file.uuid,
+            file.url,
+            file.original_filename,
+            file.mime_type,
+            file.extension,
+            file.uploader,
+            file.deduplicated,
+            file.source_url,
+            file.media_info,
+            file.public,
+            file.expires_at,
+            file.blob_id,
+        ],
+    )?;
+    if inserted == 0 {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    let file_id = conn.last_insert_rowid();
+    conn.execute("UPDATE Blob SET ref_count = ref_count + 1 WHERE id = ?1", [file.blob_id])?;
+    for tag in &file.tags {
+        conn.execute("INSERT OR IGNORE INTO FileTag (file_id, tag) VALUES (?1, ?2)", params![file_id, tag])?;
+    }
+    Ok(file_id)
+}
+
+/// Make a file readable without an API key, or not. Returns whether the file exists.
+pub fn set_file_public(conn: &Connection, uuid: &str, public: bool) -> Result<bool> {
+    let updated = conn.execute("UPDATE File SET public = ?1 WHERE uuid = ?2", params![public, uuid])?;
+    Ok(updated == 1)
+}
+
+/// Set when a file expires, or `None` for never. A file that has already
+/// expired stays expired. Returns whether the file was updated.
+pub fn set_file_expiry(conn: &Connection, uuid: &str, expires_at: Option<&str>) -> Result<bool> {
+    let updated = conn.execute("UPDATE File SET expires_at = ?1 WHERE uuid = ?2 AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)", params![expires_at, uuid])?;
+    Ok(updated == 1)
+}
+
+/// Public IDs of the files whose expiry has passed
+pub fn expired_files(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT uuid FROM File WHERE expires_at <= CURRENT_TIMESTAMP")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// What `release_file` did to a file's blob
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReleasedFile {
+    pub filepath: String, // storage key of the blob
+    pub remaining: i64,   // files still using the blob; 0 means it is gone
 }
 
-/// Insert a file record and return its ID
-pub fn insert_file(conn: &Connection, filepath: &str, url: &str, hash: &str) -> Result<i64> {
+/// Delete the file with public ID `uuid`, unlinking any job that produced it,
+/// and drop its reference to its blob. The ID is kept as deleted and can't be
+/// used again. Once no references remain the blob row is deleted too and
+/// the caller must remove the stored object. Run this
+/// in a transaction that is only committed once that has worked. Returns
+/// None if there is no such file.
+pub fn release_file(conn: &Connection, uuid: &str) -> Result<Option<ReleasedFile>> {
+    let file: Option<(i64, i64)> = conn.query_row(
+        "SELECT id, blob_id FROM File WHERE uuid = ?1",
+        [uuid],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    let (id, blob_id) = match file {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    conn.execute("UPDATE Job SET file_id = NULL WHERE file_id = ?1", [id])?;
+    conn.execute("DELETE FROM FileTag WHERE file_id = ?1", [id])?;
+    conn.execute("DELETE FROM File WHERE id = ?1", [id])?;
+    conn.execute("INSERT OR IGNORE INTO DeletedFile (uuid) VALUES (?1)", [uuid])?;
+    let (filepath, remaining): (String, i64) = conn.query_row(
+        "UPDATE Blob SET ref_count = MAX(ref_count - 1, 0) WHERE id = ?1 RETURNING filepath, ref_count",
+        [blob_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    if remaining == 0 {
+        conn.execute("DELETE FROM Blob WHERE id = ?1", [blob_id])?;
+    }
+    Ok(Some(ReleasedFile { filepath, remaining }))
+}
+
+/// Whether a file with public ID `uuid` existed and was deleted
+pub fn is_file_deleted(conn: &Connection, uuid: &str) -> Result<bool> {
+    conn.query_row("SELECT 1 FROM DeletedFile WHERE uuid = ?1", [uuid], |_| Ok(())).optional().map(|row| row.is_some())
+}
+
+fn api_key_from_row(row: &rusqlite::Row) -> Result<ApiKeyRecord> {
+    let scopes: String = row.get(3)?;
+    Ok(ApiKeyRecord {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        prefix: row.get(2)?,
+        scopes: scopes.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect(),
+        created_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+        revoked_at: row.get(6)?,
+    })
+}
+
+const API_KEY_COLUMNS: &str = "id, name, prefix, scopes, created_at, last_used_at, revoked_at";
+
+/// Store a new API key by its hash and return its ID
+pub fn insert_api_key(conn: &Connection, name: &str, key_hash: &str, prefix: &str, scopes: &[String]) -> Result<i64> {
     conn.execute(
-        "INSERT INTO File (filepath, url, hash) VALUES (?1, ?2, ?3)",
-        params![filepath, url, hash],
+        "INSERT INTO ApiKey (name, key_hash, prefix, scopes, created_at) VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP)",
+        params![name, key_hash, prefix, scopes.join(",")],
     )?;
     Ok(conn.last_insert_rowid())
 }
+
+/// Find the unrevoked key with the given hash and note that it was used
+pub fn use_api_key(conn: &Connection, key_hash: &str) -> Result<Option<ApiKeyRecord>> {
+    conn.query_row(
+        &format!(
+            "UPDATE ApiKey SET last_used_at = CURRENT_TIMESTAMP WHERE key_hash = ?1 AND revoked_at IS NULL RETURNING {}",
+            API_KEY_COLUMNS
+        ),
+        [key_hash],
+        api_key_from_row,
+    ).optional()
+}
+
+/// All API keys, revoked ones included, oldest first
+pub fn list_api_keys(conn: &Connection) -> Result<Vec<ApiKeyRecord>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM ApiKey ORDER BY id", API_KEY_COLUMNS))?;
+    let rows = stmt.query_map([], api_key_from_row)?;
+    rows.collect()
+}
+
+/// Revoke a key so it no longer authenticates. Returns false if there is no
+/// such key or it was already revoked.
+pub fn revoke_api_key(conn: &Connection, id: i64) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE ApiKey SET revoked_at = CURRENT_TIMESTAMP WHERE id = ?1 AND revoked_at IS NULL",
+        [id],
+    )?;
+    Ok(updated == 1)
+}
+
+fn upload_from_row(row: &rusqlite::Row) -> Result<UploadRecord> {
+    Ok(UploadRecord {
+        id: row.get(0)?,
+        length: row.get(1)?,
+        received: row.get(2)?,
+        metadata: row.get(3)?,
+        uploader: row.get(4)?,
+        created_at: row.get(5)?,
+        expires_at: row.get(6)?,
+        completed_at: row.get(7)?,
+        expired: row.get(8)?,
+    })
+}
+
+const UPLOAD_COLUMNS: &str =
+    "id, length, received, metadata, uploader, created_at, expires_at, completed_at, expires_at <= CURRENT_TIMESTAMP";
+
+/// Start a resumable upload of `length` bytes that expires after `ttl`
+pub fn insert_upload(conn: &Connection, id: &str, length: i64, metadata: Option<&str>, uploader: Option<&str>, ttl: std::time::Duration) -> Result<UploadRecord> {
+    conn.query_row(
+        &format!(
+            "INSERT INTO Upload (id, length, metadata, uploader, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP, datetime('now', ?5))
+             RETURNING {}",
+            UPLOAD_COLUMNS
+        ),
+        params![id, length, metadata, uploader, offset(ttl)],
+        upload_from_row,
+    )
+}
+
+pub fn get_upload(conn: &Connection, id: &str) -> Result<Option<UploadRecord>> {
+    conn.query_row(
+        &format!("SELECT {} FROM Upload WHERE id = ?1", UPLOAD_COLUMNS),
+        [id],
+        upload_from_row,
+    ).optional()
+}
+
+/// Record how much of an unfinished upload is stored and push its expiry
+/// back to `ttl` from now. Returns the updated upload, or `None` if it is
+/// gone or already complete.
+pub fn advance_upload(conn: &Connection, id: &str, received: i64, ttl: std::time::Duration) -> Result<Option<UploadRecord>> {
+    conn.query_row(
+        &format!(
+            "UPDATE Upload SET received = ?2, expires_at = datetime('now', ?3)
+             WHERE id = ?1 AND completed_at IS NULL
+             RETURNING {}",
+            UPLOAD_COLUMNS
+        ),
+        params![id, received, offset(ttl)],
+        upload_from_row,
+    ).optional()
+}
+
+/// Mark an upload as stored as a file. Returns false if it was gone or
+/// already complete.
+pub fn complete_upload(conn: &Connection, id: &str) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE Upload SET completed_at = CURRENT_TIMESTAMP WHERE id = ?1 AND completed_at IS NULL",
+        [id],
+    )?;
+    Ok(updated == 1)
+}
+
+/// Forget an upload. Returns false if there was no such upload.
+pub fn delete_upload(conn: &Connection, id: &str) -> Result<bool> {
+    let deleted = conn.execute("DELETE FROM Upload WHERE id = ?1", [id])?;
+    Ok(deleted == 1)
+}
+
+/// Forget every expired upload and return their IDs, whose partial data the
+/// caller should delete.
+pub fn delete_expired_uploads(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("DELETE FROM Upload WHERE expires_at <= CURRENT_TIMESTAMP RETURNING id")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
@@ -0,0 +1,223 @@
+//! Guarding `/download` jobs against server-side request forgery: which URLs
+//! may be fetched, and the shared HTTP client that enforces it on every hop.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::Url;
+
+use crate::config::FetchConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlockedUrl {
+    #[error("Invalid URL: {0}")]
+    Invalid(String),
+    #[error("URL scheme {0:?} is not allowed")]
+    Scheme(String),
+    #[error("Host {0} is not allowed")]
+    Host(String),
+    #[error("Host {0} has no public address")]
+    Address(String),
+    #[error("More than {0} redirects")]
+    TooManyRedirects(usize),
+}
+
+impl actix_web::ResponseError for BlockedUrl {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::BAD_REQUEST
+    }
+}
+
+/// Whether an address is on the public internet, as opposed to loopback,
+/// private, link-local, shared, reserved or otherwise special ranges.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            !(v4.is_unspecified()
+                || v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)) // carrier-grade NAT
+                || (a == 192 && b == 0 && c == 0) // IETF protocol assignments
+                || (a == 198 && (18..20).contains(&b)) // benchmarking
+                || a >= 240) // reserved
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_ip(v4.into());
+            }
+            let s = v6.segments();
+            let v4 = |high: u16, low: u16| IpAddr::V4(std::net::Ipv4Addr::from((high as u32) << 16 | low as u32));
+            // NAT64 reaches whatever IPv4 address is embedded
+            if s[0] == 0x64 && s[1] == 0xff9b && s[2..6] == [0; 4] {
+                return is_public_ip(v4(s[6], s[7]));
+            }
+            // 6to4 routes to the IPv4 address after the prefix
+            if s[0] == 0x2002 {
+                return is_public_ip(v4(s[1], s[2]));
+            }
+            // Teredo: the server's IPv4 address, then the client's inverted
+            if s[0] == 0x2001 && s[1] == 0 {
+                return is_public_ip(v4(s[2], s[3])) && is_public_ip(v4(!s[6], !s[7]));
+            }
+            !(v6.is_unspecified()
+                || v6.is_loopback()
+                || v6.is_multicast()
+                || s[..6] == [0; 6] // IPv4-compatible, deprecated
+                || (s[0] & 0xfe00) == 0xfc00 // unique local
+                || (s[0] & 0xffc0) == 0xfe80 // link-local
+                || (s[0] == 0x2001 && s[1] == 0xdb8)) // documentation
+        }
+    }
+}
+
+/// `pattern` is a host name, or `*.example.com` for any subdomain.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+        None => host == pattern,
+    }
+}
+
+/// Check a URL's scheme and host against the fetch rules. Hosts given as IP
+/// addresses are checked here; names are checked when they are resolved.
+pub fn check_url(url: &Url, config: &FetchConfig) -> Result<(), BlockedUrl> {
+    if !config.allowed_schemes.iter().any(|s| s.eq_ignore_ascii_case(url.scheme())) {
+        return Err(BlockedUrl::Scheme(url.scheme().to_string()));
+    }
+    // IPv4 hosts come back normalised (e.g. `2130706433` as `127.0.0.1`),
+    // IPv6 ones in brackets
+    let host = match url.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase(),
+        None => return Err(BlockedUrl::Invalid("URL has no host".into())),
+    };
+    if config.denied_hosts.iter().any(|p| host_matches(p, &host))
+        || (!config.allowed_hosts.is_empty() && !config.allowed_hosts.iter().any(|p| host_matches(p, &host)))
+    {
+        return Err(BlockedUrl::Host(host));
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if !config.allow_private_ips && !is_public_ip(ip) {
+            return Err(BlockedUrl::Address(host));
+        }
+    }
+    Ok(())
+}
+
+/// Parse a URL submitted for download and check it against the fetch rules.
+pub fn parse_url(url: &str, config: &FetchConfig) -> Result<Url, BlockedUrl> {
+    let url = Url::parse(url.trim()).map_err(|e| BlockedUrl::Invalid(e.to_string()))?;
+    check_url(&url, config)?;
+    Ok(url)
+}
+
+/// Resolves names like the system resolver, minus the non-public addresses.
+/// Every connection, including those for redirects, resolves through this,
+/// so a name can't be pointed at an internal address after it was checked.
+struct FilteringResolver {
+    allow_private_ips: bool,
+}
+
+impl Resolve for FilteringResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow_private_ips = self.allow_private_ips;
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?
+                .filter(|addr| allow_private_ips || is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(Box::new(BlockedUrl::Address(host)) as Box<dyn std::error::Error + Send + Sync>);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The HTTP client for `/download` jobs. Redirects are followed up to
+/// `max_redirects` and each target is checked like the original URL.
+/// Proxies from the environment are ignored, since a proxy would resolve
+/// names itself and bypass the address filter.
+pub fn client(config: &FetchConfig) -> reqwest::Result<reqwest::Client> {
+    let rules = config.clone();
+    reqwest::Client::builder()
+        .no_proxy()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .dns_resolver(Arc::new(FilteringResolver { allow_private_ips: config.allow_private_ips }))
+        .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > rules.max_redirects {
+                let max = rules.max_redirects;
+                return attempt.error(BlockedUrl::TooManyRedirects(max));
+            }
+            match check_url(attempt.url(), &rules) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        }))
+        .build()
+}
+
+/// The `BlockedUrl` behind an error, however deeply reqwest wrapped it.
+pub fn as_blocked_url<'a>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a BlockedUrl> {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(blocked) = e.downcast_ref::<BlockedUrl>() {
+            return Some(blocked);
+        }
+        source = e.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+                   "255.255.255.255", "::1", "::", "fe80::1", "fd00::1", "::ffff:127.0.0.1", "64:ff9b::a9fe:a9fe",
+                   "2002:7f00:1::1", "2002:a00:1::", "2001:0:808:808::80ff:fffe", "2001:0:a00:1::f7f7:f7f7"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:4700::1111", "::ffff:8.8.8.8", "64:ff9b::808:808",
+                   "2002:808:808::1", "2001:0:808:808::f7f7:f7f7"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_check_url() {
+        let config = FetchConfig {
+            allowed_hosts: vec!["cdn.example.com".into(), "*.media.example.org".into()],
+            denied_hosts: vec!["private.media.example.org".into()],
+            ..Default::default()
+        };
+        let check = |url: &str| parse_url(url, &config);
+        assert!(check("https://cdn.example.com/a.mp3").is_ok());
+        assert!(check("https://CDN.example.com./a.mp3").is_ok());
+        assert!(check("http://eu.media.example.org/a.mp3").is_ok());
+        assert!(matches!(check("http://media.example.org/a.mp3"), Err(BlockedUrl::Host(_))));
+        assert!(matches!(check("http://private.media.example.org/a.mp3"), Err(BlockedUrl::Host(_))));
+        assert!(matches!(check("http://evil.com/a.mp3"), Err(BlockedUrl::Host(_))));
+        assert!(matches!(check("file:///etc/passwd"), Err(BlockedUrl::Scheme(_))));
+        assert!(matches!(check("ftp://cdn.example.com/a.mp3"), Err(BlockedUrl::Scheme(_))));
+        assert!(matches!(check("not a url"), Err(BlockedUrl::Invalid(_))));
+
+        let open = FetchConfig::default();
+        for url in ["http://127.0.0.1/", "http://2130706433/", "http://[::1]:8080/", "http://169.254.169.254/latest/meta-data"] {
+            assert!(matches!(parse_url(url, &open), Err(BlockedUrl::Address(_))), "{}", url);
+        }
+        let internal = FetchConfig { allow_private_ips: true, ..Default::default() };
+        assert!(parse_url("http://10.0.0.5/a.mp3", &internal).is_ok());
+    }
+}
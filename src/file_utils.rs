@@ -1,54 +1,74 @@
-use actix_web::Error;
-use std::io::Read;
-pub fn validate_and_get_final_path(temp_path: &std::path::Path, file_path: &std::path::Path, _filename: &str) -> Result<std::path::PathBuf, Error> {
-    let mime_type = mime_guess::from_path(&temp_path).first_or_octet_stream();
-    if !is_mime_allowed(&mime_type) {
-        return cleanup_and_error(&temp_path, format!("Invalid file type: {}/{}", mime_type.type_(), mime_type.subtype()));
-    }
-    let file_head = detect_content_type(temp_path).map_err(|e| actix_web::error::ErrorBadRequest(format!("File read error: {:?}", e)))?;
-    if let Some(kind) = infer::get(&file_head) {
-        if !is_content_type_allowed(kind.mime_type()) {
-            return cleanup_and_error(&temp_path, "File type not allowed".to_string());
-        }
-        Ok(file_path.with_extension(kind.extension()))
-    } else if let Some(ext) = get_extension_fallback(_filename) {
-        Ok(file_path.with_extension(ext))
-    } else {
-        cleanup_and_error(&temp_path, "Unknown or unsupported file type".to_string())
-    }
-}
+use crate::storage::ByteStream;
+use futures_util::StreamExt;
+use crate::checksum::{Checksum, Digests, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// Number of leading bytes inspected when sniffing a file's real type.
+pub const SNIFF_LEN: usize = 8192;
 
-pub fn rename_temp_file(temp_path: &std::path::Path, final_path: &std::path::Path) -> Result<(), Error> {
-    std::fs::rename(&temp_path, &final_path).map_err(|e| actix_web::error::ErrorBadRequest(format!("Rename error: {:?}", e)))
+/// The real type of a file as determined from its content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileType {
+    pub extension: String,
+    pub mime_type: String,
 }
 
-pub fn is_mime_allowed(mime_type: &mime::Mime) -> bool {
-    let mime_str = mime_type.to_string();
-    is_mime_category_allowed(&mime_str) || is_mime_specific_allowed(&mime_str)
+#[derive(Debug, thiserror::Error)]
+pub enum FileTypeError {
+    #[error("File type not allowed: {0}")]
+    NotAllowed(String),
+    #[error("Unknown or unsupported file type")]
+    Unknown,
 }
 
-pub fn is_mime_category_allowed(mime_str: &str) -> bool {
-    ["audio/", "video/", "image/", "application/octet-stream"]
-        .iter().any(|&cat| mime_str.starts_with(cat))
+impl actix_web::ResponseError for FileTypeError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::BAD_REQUEST
+    }
 }
 
-pub fn is_mime_specific_allowed(mime_str: &str) -> bool {
-    ["application/json", "text/xml", "application/rss+xml", "application/xml", "text/xml; charset=utf-8"]
-        .contains(&mime_str)
+/// Decide the stored type of a file from its first bytes, falling back to
+/// the filename for text formats `infer` cannot recognise. Only types
+/// matching `allowed` (see `Config::allowed_mime_types`) are accepted.
+///
+/// Used for both uploads and worker downloads, so a file is judged by its
+/// content no matter how it arrived.
+pub fn validate_file_type(file_head: &[u8], filename: &str, allowed: &[String]) -> Result<FileType, FileTypeError> {
+    if let Some(kind) = infer::get(file_head) {
+        if !is_content_type_allowed(kind.mime_type(), allowed) {
+            return Err(FileTypeError::NotAllowed(kind.mime_type().to_string()));
+        }
+        Ok(FileType {
+            extension: kind.extension().to_string(),
+            mime_type: kind.mime_type().to_string(),
+        })
+    } else if let Some(ext) = get_extension_fallback(filename) {
+        let mime_type = mime_guess::from_ext(&ext).first_or_octet_stream();
+        if !is_mime_allowed(&mime_type, allowed) {
+            return Err(FileTypeError::NotAllowed(mime_type.to_string()));
+        }
+        Ok(FileType { extension: ext, mime_type: mime_type.to_string() })
+    } else {
+        Err(FileTypeError::Unknown)
+    }
 }
 
-pub fn is_content_type_allowed(mime: &str) -> bool {
-    is_content_type_prefix_allowed(mime) || is_content_type_specific_allowed(mime)
+pub fn is_mime_allowed(mime_type: &mime::Mime, allowed: &[String]) -> bool {
+    is_content_type_allowed(mime_type.essence_str(), allowed)
 }
 
-pub fn is_content_type_prefix_allowed(mime: &str) -> bool {
-    ["image/", "audio/", "video/"]
-        .iter().any(|p| mime.starts_with(p))
+pub fn is_content_type_allowed(mime: &str, allowed: &[String]) -> bool {
+    let essence = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    allowed.iter().any(|pattern| mime_pattern_matches(pattern, &essence))
 }
 
-pub fn is_content_type_specific_allowed(mime: &str) -> bool {
-    ["application/json", "application/xml", "application/rss+xml", "text/xml", "text/xml; charset=utf-8"]
-        .contains(&mime)
+/// Whether `mime` is the type named by `pattern`, or falls in its `type/*` category.
+pub fn mime_pattern_matches(pattern: &str, mime: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some(category) => mime.split('/').next() == Some(category),
+        None => pattern == mime,
+    }
 }
 
 pub fn get_extension_fallback(_filename: &str) -> Option<String> {
@@ -64,14 +84,171 @@ pub fn is_allowed_text_ext(ext: &str) -> bool {
     ["json", "xml", "rss"].contains(&ext)
 }
 
-pub fn cleanup_and_error<P: AsRef<std::path::Path>>(temp_path: P, msg: String) -> Result<std::path::PathBuf, Error> {
-    let _ = std::fs::remove_file(temp_path);
-    Err(actix_web::error::ErrorBadRequest(msg))
+/// Best guess at a file's MIME type from its first bytes, or from the client
+/// filename when the content isn't recognised.
+pub fn sniff_mime_type(file_head: &[u8], filename: &str) -> String {
+    match infer::get(file_head) {
+        Some(kind) => kind.mime_type().to_string(),
+        None => mime_guess::from_path(filename).first_or_octet_stream().to_string(),
+    }
+}
+
+/// `name` as it is if it has an extension, else with the usual extension for
+/// `content_type`, so text formats that can't be sniffed still get the
+/// extension fallback.
+pub fn with_type_extension(name: &str, content_type: &str) -> String {
+    if name.contains('.') {
+        return name.to_string();
+    }
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    match mime_guess::get_mime_extensions_str(essence).and_then(|exts| exts.first()) {
+        Some(ext) => format!("{}.{}", name, ext),
+        None => name.to_string(),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("File exceeds the {limit} byte limit for {mime_type}")]
+pub struct PayloadTooLarge {
+    pub limit: u64,
+    pub mime_type: String,
+}
+
+/// Counts bytes as a file streams in and enforces the size limit for its type.
+///
+/// Until the first `SNIFF_LEN` bytes have arrived the type is unknown, so the
+/// largest configured limit applies; after that the limit for the sniffed
+/// type does.
+#[derive(Debug)]
+pub struct SizeLimiter {
+    config: crate::Config,
+    filename: String,
+    head: Vec<u8>,
+    received: u64,
+    mime_type: Option<String>,
+}
+
+impl SizeLimiter {
+    pub fn new(config: &crate::Config, filename: &str) -> Self {
+        Self {
+            config: config.clone(),
+            filename: filename.to_string(),
+            head: Vec::with_capacity(SNIFF_LEN),
+            received: 0,
+            mime_type: None,
+        }
+    }
+
+    /// Account for another chunk of the file.
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), PayloadTooLarge> {
+        self.received += chunk.len() as u64;
+        if self.mime_type.is_none() {
+            let take = chunk.len().min(SNIFF_LEN - self.head.len());
+            self.head.extend_from_slice(&chunk[..take]);
+            if self.head.len() >= SNIFF_LEN {
+                self.mime_type = Some(sniff_mime_type(&self.head, &self.filename));
+            }
+        }
+        self.check()
+    }
+
+    /// Call once the stream has ended; short files are only typed here.
+    pub fn finish(&mut self) -> Result<u64, PayloadTooLarge> {
+        if self.mime_type.is_none() {
+            self.mime_type = Some(sniff_mime_type(&self.head, &self.filename));
+        }
+        self.check().map(|_| self.received)
+    }
+
+    fn check(&self) -> Result<(), PayloadTooLarge> {
+        let (limit, mime_type) = match &self.mime_type {
+            Some(mime_type) => (self.config.max_size_for(mime_type), mime_type.clone()),
+            None => (self.config.max_size_ceiling(), "any file".to_string()),
+        };
+        if self.received > limit {
+            return Err(PayloadTooLarge { limit, mime_type });
+        }
+        Ok(())
+    }
+}
+
+/// What was learned about a file while it streamed through a `StreamInspector`.
+#[derive(Debug, Clone)]
+pub struct Inspected {
+    pub size: u64,
+    pub hash: String, // hex SHA-256
+    pub head: Vec<u8>, // first SNIFF_LEN bytes
+    pub digests: Digests,
+}
+
+/// Enforces the size limit and computes the SHA-256 of a file in the same
+/// pass that writes it, so the bytes never have to be read back.
+#[derive(Debug)]
+pub struct StreamInspector {
+    limiter: SizeLimiter,
+    hasher: Hasher,
+}
+
+impl StreamInspector {
+    pub fn new(config: &crate::Config, filename: &str) -> Self {
+        Self {
+            limiter: SizeLimiter::new(config, filename),
+            hasher: Hasher::new(&[]),
+        }
+    }
+
+    /// Also compute whatever digests are needed to verify `checksums`.
+    pub fn with_checksums(mut self, checksums: &[Checksum]) -> Self {
+        self.hasher = Hasher::new(checksums);
+        self
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), PayloadTooLarge> {
+        self.limiter.update(chunk)?;
+        self.hasher.update(chunk);
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<Inspected, PayloadTooLarge> {
+        let mut limiter = self.limiter;
+        let size = limiter.finish()?;
+        let digests = self.hasher.finish();
+        Ok(Inspected {
+            size,
+            hash: digests.sha256_hex(),
+            head: limiter.head,
+            digests,
+        })
+    }
+}
+
+/// Pass `stream` through `inspector` on its way to storage. The stream fails
+/// as soon as the file grows past its size limit.
+pub fn inspect_stream(stream: ByteStream, inspector: Arc<Mutex<StreamInspector>>) -> ByteStream {
+    stream
+        .map(move |chunk| {
+            let chunk = chunk?;
+            inspector.lock()
+                .map_err(|_| std::io::Error::other("Stream inspector lock poisoned"))?
+                .update(&chunk)
+                .map_err(std::io::Error::other)?;
+            Ok(chunk)
+        })
+        .boxed()
+}
+
+/// Collect the result of an inspection once the stream fed through
+/// `inspect_stream` has been fully consumed and dropped.
+pub fn finish_inspection(inspector: Arc<Mutex<StreamInspector>>) -> std::io::Result<Inspected> {
+    let inspector = Arc::try_unwrap(inspector)
+        .map_err(|_| std::io::Error::other("Stream inspector still in use"))?
+        .into_inner()
+        .map_err(|_| std::io::Error::other("Stream inspector lock poisoned"))?;
+    inspector.finish().map_err(std::io::Error::other)
 }
 
-pub fn detect_content_type(temp_path: &std::path::Path) -> std::io::Result<Vec<u8>> {
-    let mut file_head = [0u8; 8192];
-    let mut file = std::fs::File::open(temp_path)?;
-    let n = file.read(&mut file_head)?;
-    Ok(file_head[..n].to_vec())
+/// The `PayloadTooLarge` behind an IO error raised by `inspect_stream` or
+/// `write_temp_file`, if that is what it was.
+pub fn as_payload_too_large(e: &std::io::Error) -> Option<&PayloadTooLarge> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<PayloadTooLarge>())
 }
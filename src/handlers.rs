@@ -1,26 +1,32 @@
 use super::AppState;
 use actix_multipart::Multipart;
 use actix_web::{
-    error, get, post, web, Error, HttpRequest, HttpResponse, Result,
+    delete, error, get, http::{header, StatusCode}, patch, post, put, route, web, Error, HttpRequest, HttpResponse, Result,
 };
 use uuid::Uuid;
-use actix_files::NamedFile;
 use crate::file_utils::*;
 use crate::multipart_utils::*;
+use crate::auth::{self, Scope};
+use crate::checksum;
 use crate::db_utils;
-use sha2::{Sha256, Digest};
-use std::fs::File;
+use crate::fetch;
+use crate::signed_url::{self, Disposition, SignedQuery};
+use crate::storage::temp_key;
 use futures_util::stream::StreamExt;
 
 #[derive(serde::Serialize)]
 pub struct JobStatusResponse {
     pub job_id: String,
     pub status: String,
-    pub file_id: Option<i64>,
+    pub file_id: Option<String>,
     pub download_url: Option<String>,
     pub error: Option<String>,
     pub created_at: Option<String>,
     pub updated_at: Option<String>,
+    pub attempts: i64,
+    pub max_attempts: i64,
+    pub next_attempt_at: Option<String>,
+    pub attempt_log: Vec<db_utils::JobAttemptRecord>,
 }
 
 #[derive(serde::Serialize)]
@@ -28,11 +34,158 @@ pub struct FileUploadResponse {
     pub file_id: String,
     pub download_url: String,
     pub message: String,
+    pub deduplicated: bool, // the content was already stored for another file
+}
+
+impl FileUploadResponse {
+    fn stored(stored: crate::ingest::StoredFile) -> Self {
+        let message = if stored.deduplicated {
+            "File uploaded successfully; identical content was already stored"
+        } else {
+            "File uploaded successfully"
+        };
+        FileUploadResponse {
+            download_url: format!("/files/{}", stored.uuid),
+            file_id: stored.uuid,
+            message: message.to_string(),
+            deduplicated: stored.deduplicated,
+        }
+    }
+}
+
+/// The outcome for one file of an upload
+#[derive(serde::Serialize)]
+pub struct FileUploadResult {
+    pub filename: String,
+    pub status: u16, // what a single-file request would have returned
+    #[serde(flatten)]
+    pub file: Option<FileUploadResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FileMetadataResponse {
+    pub file_id: String,
+    pub download_url: String,
+    pub original_filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub size: Option<i64>, // bytes
+    pub sha256: String,
+    pub created_at: Option<String>,
+    pub deduplicated: bool, // the content was already stored for another file
+    pub source_url: Option<String>, // set for files fetched by /download
+    pub media_info: Option<serde_json::Value>,
+    pub tags: Vec<String>,
+    pub public: bool, // readable without an API key
+    pub expires_at: Option<String>, // deleted after this time, UTC
+}
+
+impl FileMetadataResponse {
+    fn new(file: db_utils::FileRecord, tags: Vec<String>) -> Self {
+        FileMetadataResponse {
+            download_url: format!("/files/{}", file.uuid),
+            file_id: file.uuid,
+            original_filename: file.original_filename,
+            mime_type: file.mime_type,
+            size: file.size,
+            sha256: file.hash,
+            created_at: file.created_at,
+            deduplicated: file.deduplicated,
+            source_url: file.source_url,
+            media_info: file.media_info.as_deref().and_then(|m| serde_json::from_str(m).ok()),
+            tags,
+            public: file.public,
+            expires_at: file.expires_at,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ListFilesQuery {
+    /// MIME category (`image`) or type (`image/png`)
+    pub mime: Option<String>,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    /// RFC 3339 time or `YYYY-MM-DD`, inclusive
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    /// Substring of the original filename, ignoring case
+    pub filename: Option<String>,
+    pub tag: Option<String>,
+    pub sort: Option<db_utils::FileSort>,
+    /// Defaults to newest first for `created_at`, ascending otherwise
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FileListResponse {
+    pub files: Vec<FileMetadataResponse>,
+    pub next_cursor: Option<String>, // null on the last page
+}
+
+#[derive(serde::Deserialize)]
+pub struct UpdateFileRequest {
+    pub public: Option<bool>,
+    /// As the upload field: a future time, or empty for never
+    pub expires_at: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SignedUrlRequest {
+    /// Seconds the URL stays valid, up to `signed_urls.max_ttl_secs`
+    pub expires_in: Option<u64>,
+    /// Only accept the URL from this client address
+    pub ip: Option<std::net::IpAddr>,
+    pub disposition: Option<Disposition>,
+    /// Filename to send in Content-Disposition instead of the original one
+    pub filename: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SignedUrlResponse {
+    pub url: String,
+    pub expires_at: String, // RFC 3339
+}
+
+#[derive(serde::Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CreateApiKeyResponse {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub key: String, // only ever shown here
+}
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(serde::Deserialize)]
+pub struct UploadQuery {
+    /// Comma-separated tags for the uploaded file
+    pub tags: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
 pub struct DownloadRequest {
     pub download_url: String,
+    /// Attempts allowed for this job, capped at `retry.max_attempts`
+    pub max_attempts: Option<u32>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -47,19 +200,25 @@ pub async fn download_file(
     req: web::Json<DownloadRequest>,
     req_head: HttpRequest,
 ) -> Result<HttpResponse, Error> {
+    auth::require(&req_head, Scope::DownloadJob)?;
+    // Refuse URLs the worker would never be allowed to fetch
+    fetch::parse_url(&req.download_url, &data.config.fetch)?;
     // Generate a new job ID
     let job_id = Uuid::new_v4().to_string();
     // Get a database connection
     let conn = data.db_pool.get()
-        .map_err(|e| error::ErrorInternalServerError(e))?;
+        .map_err(error::ErrorInternalServerError)?;
+    let policy_max = data.config.retry.max_attempts;
+    let max_attempts = req.max_attempts.unwrap_or(policy_max).clamp(1, policy_max);
     // Insert the new job with NotStarted status and null file_id
     db_utils::insert_job(
         &conn,
         &job_id,
         &db_utils::JobStatus::NotStarted,
         None,
-        &req.download_url
-    ).map_err(|e| error::ErrorInternalServerError(e))?;
+        &req.download_url,
+        max_attempts,
+    ).map_err(error::ErrorInternalServerError)?;
     // Compose full status URL
     let conn_info = req_head.connection_info();
     let scheme = conn_info.scheme();
@@ -75,22 +234,26 @@ pub async fn download_file(
 pub async fn get_job_status(
     path: web::Path<String>,
     data: web::Data<AppState>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::DownloadJob)?;
     let job_id = path.into_inner();
     let conn = data.db_pool.get()
-        .map_err(|e| error::ErrorInternalServerError(e))?;
+        .map_err(error::ErrorInternalServerError)?;
     
     match db_utils::get_job_by_id(&conn, &job_id) {
         Ok(Some(job)) => {
-            let (status, _error): (&str, Option<String>) = match job.status {
-                db_utils::JobStatus::NotStarted => ("NotStarted", None),
-                db_utils::JobStatus::Running => ("Running", None),
-                db_utils::JobStatus::Completed => ("Completed", None),
+            // Expose the public UUID of the resulting file, never the numeric row ID
+            let file_uuid = match job.file_id {
+                Some(file_id) => Some(db_utils::get_file_by_id(&conn, file_id)
+                    .map(|file| file.uuid)
+                    .map_err(error::ErrorInternalServerError)?),
+                None => None,
             };
             
             // Always return the UUID-based download URL for completed jobs
             let download_url = if job.status == db_utils::JobStatus::Completed {
-                Some(format!("/files/{}", job.id))
+                Some(format!("/files/{}", file_uuid.as_deref().unwrap_or(&job.id)))
             } else {
                 Some(job.download_url.clone())
             };
@@ -108,14 +271,21 @@ pub async fn get_job_status(
                 |row| row.get(0)
             ).ok();
             
+            let attempt_log = db_utils::get_job_attempts(&conn, &job.id)
+                .map_err(error::ErrorInternalServerError)?;
+            
             Ok(HttpResponse::Ok().json(JobStatusResponse {
                 job_id: job.id,
-                status: status.to_string(),
-                file_id: None, // Always None, never expose numeric file_id
+                status: job.status.to_string(),
+                file_id: file_uuid,
                 download_url,
                 error: job.error,
                 created_at,
                 updated_at,
+                attempts: job.attempts,
+                max_attempts: job.max_attempts,
+                next_attempt_at: job.next_attempt_at,
+                attempt_log,
             }))
         },
         Ok(None) => {
@@ -129,98 +299,759 @@ pub async fn get_job_status(
     }
 }
 
+#[delete("/jobs/{job_id}")]
+pub async fn cancel_job(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::DownloadJob)?;
+    let job_id = path.into_inner();
+    let conn = data.db_pool.get()
+        .map_err(error::ErrorInternalServerError)?;
+    
+    match db_utils::cancel_job(&conn, &job_id).map_err(error::ErrorInternalServerError)? {
+        Some(previous) if !previous.is_finished() => {
+            // Stop the download if the worker has already picked it up
+            if let Some(worker) = &data.worker {
+                worker.cancel(&job_id);
+            }
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "job_id": job_id,
+                "status": db_utils::JobStatus::Cancelled,
+            })))
+        },
+        Some(status) => {
+            Ok(HttpResponse::Conflict().json(serde_json::json!({
+                "error": format!("Job already {}", status),
+                "job_id": job_id,
+                "status": status,
+            })))
+        },
+        None => {
+            Ok(HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Job not found"
+            })))
+        }
+    }
+}
+
 #[post("/upload")]
 pub async fn upload_file(
     mut payload: Multipart,
     data: web::Data<AppState>,
+    query: web::Query<UploadQuery>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
-    
-    let file_id = Uuid::new_v4().to_string();
-    
-    let file_path = data.media_path.join(&file_id);
-    
-    let temp_path = file_path.with_extension("tmp");
-    
-    if let Some(item) = payload.next().await {
-        let field = item.map_err(|e| error::ErrorBadRequest(format!("Multipart error: {}", e)))?;
-        let _filename = get_filename_from_field(&field);
-        eprintln!("DEBUG: filename={:?}", _filename);
-        write_temp_file(field, &temp_path).await?;
-        eprintln!("DEBUG: Finished writing file: {:?}", temp_path);
-        let final_path = validate_and_get_final_path(&temp_path, &file_path, &_filename)?;
-        rename_temp_file(&temp_path, &final_path)?;
-        eprintln!("DEBUG: Renamed file to {:?}", final_path);
-        let download_url = format!("/files/{}", file_id);
-
-        // Calculate hash
-        let mut file = File::open(&final_path).map_err(|e| error::ErrorInternalServerError(e))?;
-        let mut hasher = Sha256::new();
-        std::io::copy(&mut file, &mut hasher).map_err(|e| error::ErrorInternalServerError(e))?;
-        let hash = format!("{:x}", hasher.finalize());
-
-        // Insert into DB or deduplicate
-        let conn = data.db_pool.get().map_err(|e| error::ErrorInternalServerError(e))?;
-        
-        // Check for existing file with same hash
-        match db_utils::get_filepath_by_hash(&conn, &hash).map_err(|e| error::ErrorInternalServerError(e))? {
-            Some(existing_path) => {
-                // Duplicate: delete new file, use original path in DB
-                let _ = std::fs::remove_file(&final_path);
-                
-                // For duplicates, return 200 OK with the original file's URL
-                let original_file_id = std::path::Path::new(&existing_path)
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("")
-                    .to_string();
-                    
-                let download_url = format!("/files/{}", original_file_id);
-                Ok(HttpResponse::Ok().json(FileUploadResponse {
-                    file_id: original_file_id,
-                    download_url,
-                    message: "File already exists".to_string(),
-                }))
+    auth::require(&req, Scope::Upload)?;
+
+    let uploader = auth::uploader(&req);
+    let mut fields = UploadFields {
+        tags: crate::ingest::normalize_tags(query.tags.as_deref().unwrap_or("").split(',')),
+        ..Default::default()
+    };
+    let mut results = Vec::new();
+    while let Some(item) = payload.next().await {
+        let mut field = item.map_err(|e| error::ErrorBadRequest(format!("Multipart error: {}", e)))?;
+        if field.content_disposition().get_filename().is_none() {
+            let name = field.content_disposition().get_name().unwrap_or_default().to_string();
+            let value = read_text_field(&mut field).await?;
+            fields.set(&name, value);
+            continue;
+        }
+
+        let filename = fields.filename.take().unwrap_or_else(|| get_filename_from_field(&field));
+        log::debug!("filename={:?}", filename);
+        let stored = match fields.error() {
+            Some(e) => {
+                // Skip the file's content to get to the next part
+                while let Some(chunk) = field.next().await {
+                    chunk.map_err(|e| error::ErrorBadRequest(format!("Multipart error: {}", e)))?;
+                }
+                Err(error::ErrorBadRequest(e))
+            }
+            None => store_upload(field, &data, crate::ingest::FileInfo {
+                uuid: Uuid::new_v4().to_string(),
+                original_filename: Some(filename.clone()),
+                uploader: uploader.clone(),
+                tags: fields.tags.clone(),
+                public: fields.public,
+                expires_at: fields.expires_at.clone(),
+                ..Default::default()
+            }).await,
+        };
+        results.push(match stored {
+            Ok(stored) => {
+                log::debug!("Stored file {} (deduplicated: {})", stored.uuid, stored.deduplicated);
+                FileUploadResult {
+                    filename,
+                    status: StatusCode::CREATED.as_u16(),
+                    file: Some(FileUploadResponse::stored(stored)),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                log::debug!("Upload of {} failed: {}", filename, e);
+                FileUploadResult {
+                    filename,
+                    status: e.as_response_error().status_code().as_u16(),
+                    file: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        });
+    }
+
+    if results.is_empty() {
+        log::debug!("No file provided in multipart");
+        return Err(error::ErrorBadRequest("No file provided"));
+    }
+    // One status for the lot if all the files agree, 207 for a mix of
+    // successes and failures
+    let first = results[0].status;
+    let status = if results.iter().all(|r| r.status == first) {
+        StatusCode::from_u16(first).unwrap_or(StatusCode::BAD_REQUEST)
+    } else if results.iter().all(|r| r.file.is_none()) {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    Ok(HttpResponse::build(status).json(results))
+}
+
+/// Form fields sent alongside the files of an upload. Each applies to the
+/// files that come after it in the form, except `filename`, which only
+/// renames the next one.
+#[derive(Default)]
+struct UploadFields {
+    tags: Vec<String>,
+    public: bool,
+    expires_at: Option<String>,
+    filename: Option<String>,
+    invalid: std::collections::BTreeMap<String, String>, // field name -> error
+}
+
+impl UploadFields {
+    fn set(&mut self, name: &str, value: Result<String, String>) {
+        let value = match value {
+            Ok(value) => value,
+            Err(e) => {
+                self.invalid.insert(name.to_string(), e);
+                return;
+            }
+        };
+        let value = value.trim();
+        let parsed = match name {
+            "tags" => {
+                self.tags = crate::ingest::normalize_tags(value.split(','));
+                Ok(())
+            }
+            "visibility" => match value {
+                "public" | "private" => {
+                    self.public = value == "public";
+                    Ok(())
+                }
+                _ => Err(format!("Invalid visibility {:?}, expected public or private", value)),
             },
-            None => {
-                // New file: insert as normal and return 201 Created
-                db_utils::insert_file(&conn, final_path.to_string_lossy().as_ref(), &download_url, &hash)
-                    .map_err(|e| error::ErrorInternalServerError(e))?;
-                    
-                Ok(HttpResponse::Created().json(FileUploadResponse {
-                    file_id: file_id.clone(),
-                    download_url: format!("/files/{}", file_id),
-                    message: "File uploaded successfully".to_string(),
-                }))
+            "expires_at" => parse_expiry(value).map(|time| self.expires_at = time),
+            "filename" => {
+                self.filename = Some(value.to_string()).filter(|f| !f.is_empty());
+                Ok(())
             }
+            _ => {
+                log::debug!("Ignoring unknown form field {:?}", name);
+                Ok(())
+            }
+        };
+        match parsed {
+            Ok(()) => self.invalid.remove(name),
+            Err(e) => self.invalid.insert(name.to_string(), e),
+        };
+    }
+
+    /// Why the files that follow can't be stored, if a field was invalid
+    fn error(&self) -> Option<String> {
+        self.invalid.iter().next().map(|(name, e)| format!("Form field {}: {}", name, e))
+    }
+}
+
+/// Longest form field value accepted alongside an upload
+const MAX_FIELD_LEN: usize = 4096;
+
+/// Read a text form field. A value that is too long or not UTF-8 comes back
+/// as the inner error; only a broken multipart body fails the request.
+async fn read_text_field(field: &mut actix_multipart::Field) -> Result<Result<String, String>, Error> {
+    let mut value = Vec::new();
+    let mut too_long = false;
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| error::ErrorBadRequest(format!("Multipart error: {}", e)))?;
+        too_long |= value.len() + chunk.len() > MAX_FIELD_LEN;
+        if !too_long {
+            value.extend_from_slice(&chunk);
         }
-    } else {
-        eprintln!("DEBUG: No file provided in multipart");
-        Err(error::ErrorBadRequest("No file provided"))
     }
+    if too_long {
+        return Ok(Err(format!("Longer than {} bytes", MAX_FIELD_LEN)));
+    }
+    Ok(String::from_utf8(value).map_err(|_| "Not valid UTF-8".to_string()))
+}
+
+/// Validate one file of an upload and store it as a new file. Identical
+/// content shares one blob.
+async fn store_upload(
+    field: actix_multipart::Field,
+    data: &AppState,
+    info: crate::ingest::FileInfo,
+) -> Result<crate::ingest::StoredFile, Error> {
+    let filename = info.original_filename.clone().unwrap_or_default();
+    let checksums = checksum::from_headers(field.headers())?;
+    let staged = stage_upload(field, data, &filename, &info.uuid, &checksums).await?;
+    Ok(crate::ingest::store_file(data, &staged, info).await?)
+}
+
+/// Write an uploaded body to a temporary object, then check its type and
+/// the client's checksums and hash it, ready to be stored as file `uuid`.
+/// Nothing is left behind if it is rejected.
+async fn stage_upload<S, E>(
+    body: S,
+    data: &AppState,
+    filename: &str,
+    uuid: &str,
+    checksums: &[checksum::Checksum],
+) -> Result<crate::ingest::Staged, Error>
+where
+    S: futures_util::Stream<Item = Result<bytes::Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let temp_key = temp_key(uuid);
+    let staged = stage(body, data, filename, &temp_key, checksums).await;
+    if staged.is_err() {
+        let _ = data.storage.delete(&temp_key).await;
+    }
+    staged
+}
+
+async fn stage<S, E>(
+    body: S,
+    data: &AppState,
+    filename: &str,
+    temp_key: &str,
+    checksums: &[checksum::Checksum],
+) -> Result<crate::ingest::Staged, Error>
+where
+    S: futures_util::Stream<Item = Result<bytes::Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    // Hash, size-check and sniff the body in the same pass that writes it
+    let inspector = StreamInspector::new(&data.config, filename).with_checksums(checksums);
+    let inspected = write_temp_file(body, data.storage.as_ref(), temp_key, inspector).await?;
+    log::debug!("Finished writing file: {:?}", temp_key);
+    let file_type = validate_file_type(&inspected.head, filename, &data.config.allowed_mime_types)?;
+    inspected.digests.verify(checksums)?;
+
+    let media_info = crate::media_info::extract(&inspected.head, &file_type.mime_type);
+    Ok(crate::ingest::Staged {
+        temp_key: temp_key.to_string(),
+        hash: inspected.hash,
+        size: inspected.size,
+        file_type,
+        media_info,
+    })
+}
+
+#[put("/files")]
+pub async fn put_file(
+    payload: web::Payload,
+    data: web::Data<AppState>,
+    query: web::Query<UploadQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Upload)?;
+    store_body(payload, &data, &query, &req, Uuid::new_v4().to_string()).await
+}
+
+/// Create a file under an ID the client chose, so a retried request can't
+/// store it twice
+#[put("/files/{file_id}")]
+pub async fn put_file_with_id(
+    path: web::Path<String>,
+    payload: web::Payload,
+    data: web::Data<AppState>,
+    query: web::Query<UploadQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Upload)?;
+    let file_id = Uuid::parse_str(&path)
+        .map_err(|_| error::ErrorBadRequest("File IDs must be UUIDs"))?
+        .to_string();
+    // Refuse a deleted ID before reading the body; inserting checks again
+    let deleted = {
+        let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+        db_utils::is_file_deleted(&conn, &file_id).map_err(error::ErrorInternalServerError)?
+    };
+    if deleted {
+        return Err(deleted_id());
+    }
+    store_body(payload, &data, &query, &req, file_id).await
+}
+
+/// Store a raw request body as file `file_id`. If the ID is taken by a file
+/// with the same content this is a repeat of an earlier request and that
+/// file is returned with 200; different content is a conflict. IDs of
+/// deleted files are gone for good.
+async fn store_body(
+    payload: web::Payload,
+    data: &AppState,
+    query: &UploadQuery,
+    req: &HttpRequest,
+    file_id: String,
+) -> Result<HttpResponse, Error> {
+    let filename = body_filename(req);
+    log::debug!("filename={:?}", filename);
+    let uploader = auth::uploader(req);
+    let checksums = checksum::from_headers(req.headers())?;
+    let staged = stage_upload(payload, data, &filename, &file_id, &checksums).await?;
+    let stored = crate::ingest::store_file(data, &staged, crate::ingest::FileInfo {
+        uuid: file_id.clone(),
+        original_filename: Some(filename),
+        uploader,
+        tags: crate::ingest::normalize_tags(query.tags.as_deref().unwrap_or("").split(',')),
+        ..Default::default()
+    }).await;
+    let e = match stored {
+        Ok(stored) => {
+            log::debug!("Stored file {} (deduplicated: {})", stored.uuid, stored.deduplicated);
+            return Ok(HttpResponse::Created().json(FileUploadResponse::stored(stored)));
+        }
+        Err(e) => e,
+    };
+
+    let (existing, deleted) = {
+        let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+        (
+            db_utils::get_file_by_uuid(&conn, &file_id).map_err(error::ErrorInternalServerError)?,
+            db_utils::is_file_deleted(&conn, &file_id).map_err(error::ErrorInternalServerError)?,
+        )
+    };
+    match existing {
+        None if deleted => Err(deleted_id()),
+        Some(file) if file.hash == staged.hash => Ok(HttpResponse::Ok().json(FileUploadResponse {
+            download_url: format!("/files/{}", file.uuid),
+            file_id: file.uuid,
+            message: "File already exists with identical content".to_string(),
+            deduplicated: file.deduplicated,
+        })),
+        Some(_) => Err(error::ErrorConflict("A file with this ID already exists with different content")),
+        // Taken by a file that has expired but not been purged yet
+        None if is_constraint_violation(&e) => Err(error::ErrorConflict("A file with this ID already exists")),
+        None => Err(e.into()),
+    }
+}
+
+fn deleted_id() -> Error {
+    error::ErrorGone("A file with this ID was deleted; IDs are never reused")
+}
+
+fn is_constraint_violation(e: &crate::ingest::StoreError) -> bool {
+    matches!(
+        e,
+        crate::ingest::StoreError::Db(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation
+    )
+}
+
+/// Filename for a raw upload: the `Content-Disposition` filename, else
+/// `X-Filename`. Without an extension one is derived from `Content-Type`.
+fn body_filename(req: &HttpRequest) -> String {
+    use actix_web::http::header::Header;
+    let disposition = header::ContentDisposition::parse(req).ok().and_then(|cd| {
+        cd.get_filename().map(str::to_string)
+            .or_else(|| cd.get_filename_ext().map(|ext| String::from_utf8_lossy(&ext.value).into_owned()))
+    });
+    let name = disposition
+        .or_else(|| req.headers().get("x-filename").and_then(|v| v.to_str().ok()).map(str::to_string))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "file".to_string());
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    with_type_extension(&name, content_type)
 }
 
-#[get("/files/{file_id}")]
+#[route("/files/{file_id}", method = "GET", method = "HEAD")]
 pub async fn serve_file(
     path: web::Path<String>,
+    query: web::Query<SignedQuery>,
     data: web::Data<AppState>,
     req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let file_id = path.into_inner();
-    let dir_entries = std::fs::read_dir(&data.media_path)?;
+    // A signed URL stands in for an API key, and a bad one is never ignored.
+    // IP bindings are checked against the peer, which can't be spoofed
+    // with forwarding headers.
+    let grant = match query.sig {
+        Some(_) => Some(signed_url::verify(
+            &data.config.signed_urls.keys,
+            &file_id,
+            &query,
+            req.peer_addr().map(|addr| addr.ip()),
+            chrono::Utc::now().timestamp(),
+        )?),
+        None => None,
+    };
+    let file = {
+        let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+        db_utils::get_file_by_uuid(&conn, &file_id).map_err(error::ErrorInternalServerError)?
+    };
+    // Without the read scope, missing and private files look the same
+    if grant.is_none() && !file.as_ref().is_some_and(|f| f.public) {
+        auth::require(&req, Scope::Read)?;
+    }
+    
+    if let Some(file) = file {
+        let size = match file.size {
+            Some(size) => size as u64,
+            None => data.storage.stat(&file.filepath).await?
+                .ok_or_else(|| error::ErrorNotFound("File not found"))?
+                .size,
+        };
+        let content_type: mime::Mime = file.mime_type.as_deref()
+            .and_then(|m| m.parse().ok())
+            .unwrap_or_else(|| mime_guess::from_path(&file.filepath).first_or_octet_stream());
+        return crate::http_utils::file_response(&req, data.storage.clone(), crate::http_utils::ServedFile {
+            key: file.filepath,
+            size,
+            hash: file.hash,
+            content_type,
+            filename: grant.as_ref().and_then(|g| g.filename.clone()).or(file.original_filename),
+            disposition: match grant.as_ref().and_then(|g| g.disposition) {
+                Some(Disposition::Attachment) => header::DispositionType::Attachment,
+                _ => header::DispositionType::Inline,
+            },
+            // Responses to signed URLs may differ by their parameters
+            cache_control: if grant.is_none() && (file.public || !data.config.auth.enabled) {
+                crate::http_utils::PUBLIC_CACHE_CONTROL
+            } else {
+                crate::http_utils::PRIVATE_CACHE_CONTROL
+            },
+        }).await;
+    }
     
-    for entry in dir_entries {
-        let entry = entry?;
-        let path = entry.path();
-        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
-            if stem == file_id {
-                
-                let named_file = NamedFile::open_async(path).await?;
-                return Ok(named_file.into_response(&req));
+    Err(error::ErrorNotFound("File not found"))
+}
+
+/// Stored content by its SHA-256 (lowercase hex), as given by
+/// `GET /files/{id}/meta`. Unlike a file ID the URL can't come to mean other
+/// bytes, so it may be cached for good.
+#[route("/blobs/{sha256}", method = "GET", method = "HEAD")]
+pub async fn serve_blob(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let hash = path.into_inner();
+    let file = if hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+        db_utils::get_file_by_hash(&conn, &hash).map_err(error::ErrorInternalServerError)?
+    } else {
+        None
+    };
+    // Readable by anyone if any file with this content is public
+    if !file.as_ref().is_some_and(|f| f.public) {
+        auth::require(&req, Scope::Read)?;
+    }
+    let file = file.ok_or_else(|| error::ErrorNotFound("Content not found"))?;
+    let size = match file.size {
+        Some(size) => size as u64,
+        None => data.storage.stat(&file.filepath).await?
+            .ok_or_else(|| error::ErrorNotFound("Content not found"))?
+            .size,
+    };
+    let content_type: mime::Mime = file.mime_type.as_deref()
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    crate::http_utils::file_response(&req, data.storage.clone(), crate::http_utils::ServedFile {
+        key: file.filepath,
+        size,
+        hash: file.hash,
+        content_type,
+        filename: None,
+        disposition: header::DispositionType::Inline,
+        cache_control: if file.public || !data.config.auth.enabled {
+            crate::http_utils::IMMUTABLE_CACHE_CONTROL
+        } else {
+            crate::http_utils::PRIVATE_IMMUTABLE_CACHE_CONTROL
+        },
+    }).await
+}
+
+#[post("/files/{file_id}/signed-url")]
+pub async fn create_signed_url(
+    path: web::Path<String>,
+    body: web::Json<SignedUrlRequest>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Read)?;
+    let settings = &data.config.signed_urls;
+    let Some(key) = settings.keys.first() else {
+        return Err(error::ErrorServiceUnavailable("Signed URLs are not configured"));
+    };
+    let file_id = path.into_inner();
+    let exists = {
+        let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+        db_utils::get_file_by_uuid(&conn, &file_id).map_err(error::ErrorInternalServerError)?.is_some()
+    };
+    if !exists {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "File not found"
+        })));
+    }
+
+    let body = body.into_inner();
+    let ttl = body.expires_in.unwrap_or(settings.default_ttl_secs);
+    if ttl == 0 || ttl > settings.max_ttl_secs {
+        return Err(error::ErrorBadRequest(format!("expires_in must be between 1 and {}", settings.max_ttl_secs)));
+    }
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(ttl as i64);
+    let path = signed_url::sign(key, &signed_url::Grant {
+        file_id,
+        expires: expires_at.timestamp(),
+        ip: body.ip,
+        disposition: body.disposition,
+        filename: body.filename.filter(|f| !f.is_empty()),
+    });
+    let conn_info = req.connection_info();
+    Ok(HttpResponse::Ok().json(SignedUrlResponse {
+        url: format!("{}://{}{}", conn_info.scheme(), conn_info.host(), path),
+        expires_at: expires_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    }))
+}
+
+#[get("/files/{file_id}/meta")]
+pub async fn get_file_metadata(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let file_id = path.into_inner();
+    let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+    let file = db_utils::get_file_by_uuid(&conn, &file_id).map_err(error::ErrorInternalServerError)?;
+    if !file.as_ref().is_some_and(|f| f.public) {
+        auth::require(&req, Scope::Read)?;
+    }
+    let file = match file {
+        Some(file) => file,
+        None => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json!({
+                "error": "File not found"
+            })));
+        }
+    };
+    let tags = db_utils::get_file_tags(&conn, file.id).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(FileMetadataResponse::new(file, tags)))
+}
+
+#[get("/files")]
+pub async fn list_files(
+    query: web::Query<ListFilesQuery>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Read)?;
+    let query = query.into_inner();
+    let sort = query.sort.unwrap_or_default();
+    let descending = match query.order {
+        Some(order) => matches!(order, SortOrder::Desc),
+        None => sort == db_utils::FileSort::CreatedAt,
+    };
+    let cursor = match &query.cursor {
+        Some(cursor) => {
+            let cursor = decode_cursor(cursor).ok_or_else(|| error::ErrorBadRequest("Invalid cursor"))?;
+            if cursor.sort != sort || cursor.descending != descending {
+                return Err(error::ErrorBadRequest("Cursor belongs to a listing with a different order"));
             }
+            Some(cursor)
+        }
+        None => None,
+    };
+    let timestamp = |value: &Option<String>, end_of_day: bool| match value {
+        Some(value) => parse_timestamp(value, end_of_day)
+            .map(Some)
+            .ok_or_else(|| error::ErrorBadRequest(format!("Invalid date: {}", value))),
+        None => Ok(None),
+    };
+    let file_query = db_utils::FileQuery {
+        mime: query.mime.filter(|m| !m.is_empty()),
+        min_size: query.min_size,
+        max_size: query.max_size,
+        created_after: timestamp(&query.created_after, false)?,
+        created_before: timestamp(&query.created_before, true)?,
+        filename: query.filename.filter(|f| !f.is_empty()),
+        tag: query.tag.filter(|t| !t.is_empty()),
+        sort,
+        descending,
+        limit: query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+        cursor,
+    };
+
+    let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+    let page = db_utils::list_files(&conn, &file_query).map_err(error::ErrorInternalServerError)?;
+    let mut files = Vec::with_capacity(page.files.len());
+    for file in page.files {
+        let tags = db_utils::get_file_tags(&conn, file.id).map_err(error::ErrorInternalServerError)?;
+        files.push(FileMetadataResponse::new(file, tags));
+    }
+    Ok(HttpResponse::Ok().json(FileListResponse {
+        files,
+        next_cursor: page.next_cursor.as_ref().map(encode_cursor),
+    }))
+}
+
+/// Cursors are opaque to clients: URL-safe base64 of the JSON position
+fn encode_cursor(cursor: &db_utils::FileCursor) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str) -> Option<db_utils::FileCursor> {
+    use base64::Engine;
+    let json = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// Parse an RFC 3339 time, or a `YYYY-MM-DD` date meaning the start (or end)
+/// of that day in UTC, into the format SQLite stores timestamps in
+fn parse_timestamp(value: &str, end_of_day: bool) -> Option<String> {
+    let time = match chrono::DateTime::parse_from_rfc3339(value) {
+        Ok(time) => time.naive_utc(),
+        Err(_) => {
+            let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+            if end_of_day { date.and_hms_opt(23, 59, 59)? } else { date.and_hms_opt(0, 0, 0)? }
         }
+    };
+    Some(time.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// Parse an `expires_at` value, which must lie in the future: a file that has
+/// already expired would be stored only to be hidden straight away. Empty
+/// means the file never expires.
+fn parse_expiry(value: &str) -> Result<Option<String>, String> {
+    if value.is_empty() {
+        return Ok(None);
     }
+    let time = parse_timestamp(value, false).ok_or_else(|| format!("Invalid expires_at: {}", value))?;
+    // Both in SQLite's format, which sorts like the times it stands for
+    if time <= chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string() {
+        return Err(format!("expires_at {} is not in the future", value));
+    }
+    Ok(Some(time))
+}
+
+#[delete("/files/{file_id}")]
+pub async fn delete_file(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Admin)?;
+    let file_id = path.into_inner();
+    let released = match crate::ingest::delete_file(&data, &file_id).await? {
+        Some(released) => released,
+        None => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json!({
+                "error": "File not found"
+            })));
+        }
+    };
     
-    Err(error::ErrorNotFound("File not found"))
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "file_id": file_id,
+        "references_remaining": released.remaining,
+    })))
+}
+
+#[patch("/files/{file_id}")]
+pub async fn update_file(
+    path: web::Path<String>,
+    body: web::Json<UpdateFileRequest>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Admin)?;
+    let file_id = path.into_inner();
+    let expires_at = body.expires_at.as_deref().map(|value| parse_expiry(value.trim()))
+        .transpose()
+        .map_err(error::ErrorBadRequest)?;
+    let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+    if let Some(public) = body.public {
+        db_utils::set_file_public(&conn, &file_id, public).map_err(error::ErrorInternalServerError)?;
+    }
+    if let Some(expires_at) = expires_at {
+        db_utils::set_file_expiry(&conn, &file_id, expires_at.as_deref()).map_err(error::ErrorInternalServerError)?;
+    }
+    match db_utils::get_file_by_uuid(&conn, &file_id).map_err(error::ErrorInternalServerError)? {
+        Some(file) => {
+            let tags = db_utils::get_file_tags(&conn, file.id).map_err(error::ErrorInternalServerError)?;
+            Ok(HttpResponse::Ok().json(FileMetadataResponse::new(file, tags)))
+        }
+        None => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "File not found"
+        }))),
+    }
+}
+
+#[post("/admin/keys")]
+pub async fn create_api_key(
+    body: web::Json<CreateApiKeyRequest>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Admin)?;
+    let body = body.into_inner();
+    if body.name.trim().is_empty() || body.scopes.is_empty() {
+        return Err(error::ErrorBadRequest("A key needs a name and at least one scope"));
+    }
+    let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+    let (id, key) = auth::mint_key(&conn, body.name.trim(), &body.scopes).map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Created().json(CreateApiKeyResponse {
+        id,
+        name: body.name.trim().to_string(),
+        scopes: body.scopes,
+        key,
+    }))
+}
+
+#[get("/admin/keys")]
+pub async fn list_api_keys(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Admin)?;
+    let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+    let keys = db_utils::list_api_keys(&conn).map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+#[delete("/admin/keys/{key_id}")]
+pub async fn revoke_api_key(
+    path: web::Path<i64>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    auth::require(&req, Scope::Admin)?;
+    let key_id = path.into_inner();
+    let conn = data.db_pool.get().map_err(error::ErrorInternalServerError)?;
+    if db_utils::revoke_api_key(&conn, key_id).map_err(error::ErrorInternalServerError)? {
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "id": key_id,
+            "revoked": true,
+        })))
+    } else {
+        Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Key not found or already revoked"
+        })))
+    }
 }
 
 #[get("/about")]
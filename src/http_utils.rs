@@ -0,0 +1,265 @@
+use actix_web::http::header::{self, EntityTag, Header};
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use std::sync::Arc;
+
+use crate::storage::{self, StorageBackend};
+
+/// A file ID can be deleted, made private or given new content, so caches
+/// must revalidate every time. The ETag makes that a cheap 304.
+pub const PUBLIC_CACHE_CONTROL: &str = "public, no-cache";
+
+/// Like `PUBLIC_CACHE_CONTROL`, for files that need an API key: only the
+/// client's own cache may keep them.
+pub const PRIVATE_CACHE_CONTROL: &str = "private, no-cache";
+
+/// Content addressed by its SHA-256 can't change, so caches keep it for a
+/// year without asking again.
+pub const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Like `IMMUTABLE_CACHE_CONTROL`, for content that needs an API key.
+pub const PRIVATE_IMMUTABLE_CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+
+/// More ranges than this in one request are ignored and the whole file is sent.
+pub const MAX_RANGES: usize = 16;
+
+/// Strong ETag for a file, derived from its SHA-256.
+pub fn etag_for(hash: &str) -> EntityTag {
+    EntityTag::new_strong(hash.to_string())
+}
+
+/// Outcome of evaluating `If-Match` and `If-None-Match` against a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+/// Evaluate the conditional request headers of a GET or HEAD (RFC 9110 §13.2.2).
+pub fn check_preconditions(req: &HttpRequest, etag: &EntityTag) -> Precondition {
+    // A missing list header parses as an empty list, so check presence first
+    if req.headers().contains_key(header::IF_MATCH) {
+        match header::IfMatch::parse(req) {
+            Ok(header::IfMatch::Any) => {}
+            Ok(header::IfMatch::Items(tags)) if tags.iter().any(|tag| tag.strong_eq(etag)) => {}
+            _ => return Precondition::Failed,
+        }
+    }
+    if !req.headers().contains_key(header::IF_NONE_MATCH) {
+        return Precondition::Proceed;
+    }
+    match header::IfNoneMatch::parse(req) {
+        Ok(header::IfNoneMatch::Any) => Precondition::NotModified,
+        Ok(header::IfNoneMatch::Items(tags)) if tags.iter().any(|tag| tag.weak_eq(etag)) => Precondition::NotModified,
+        _ => Precondition::Proceed,
+    }
+}
+
+/// What part of a file a request asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,
+    /// Sorted, non-overlapping, end-inclusive byte ranges.
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+/// Work out the ranges requested by `Range`, honouring `If-Range`. Malformed
+/// headers, non-byte units and stale `If-Range` validators fall back to the
+/// full file, as do requests for more than `MAX_RANGES` ranges.
+pub fn requested_ranges(req: &HttpRequest, etag: &EntityTag, size: u64) -> RangeRequest {
+    let specs = match header::Range::parse(req) {
+        Ok(header::Range::Bytes(specs)) => specs,
+        _ => return RangeRequest::Full,
+    };
+    if req.headers().contains_key(header::IF_RANGE) {
+        match header::IfRange::parse(req) {
+            Ok(header::IfRange::EntityTag(tag)) if tag.strong_eq(etag) => {}
+            _ => return RangeRequest::Full,
+        }
+    }
+    let ranges: Vec<(u64, u64)> = specs.iter()
+        .filter_map(|spec| spec.to_satisfiable_range(size))
+        .collect();
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    let ranges = coalesce_ranges(ranges);
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    RangeRequest::Partial(ranges)
+}
+
+/// Sort ranges and merge those that overlap or touch, so no byte is sent twice.
+pub fn coalesce_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// A stored file to be sent over HTTP.
+pub struct ServedFile {
+    pub key: String,
+    pub size: u64,
+    pub hash: String,
+    pub content_type: mime::Mime,
+    pub filename: Option<String>,
+    pub disposition: header::DispositionType,
+    /// One of the `*_CACHE_CONTROL` policies above
+    pub cache_control: &'static str,
+}
+
+/// Build the response for a GET or HEAD of `file`, handling conditional and
+/// range requests. The body is only read from storage for GET.
+pub async fn file_response(req: &HttpRequest, storage: Arc<dyn StorageBackend>, file: ServedFile) -> actix_web::Result<HttpResponse> {
+    let etag = etag_for(&file.hash);
+    let cache_control = file.cache_control;
+    match check_preconditions(req, &etag) {
+        Precondition::Failed => {
+            return Ok(HttpResponse::PreconditionFailed().insert_header(header::ETag(etag)).finish());
+        }
+        Precondition::NotModified => {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(header::ETag(etag))
+                .insert_header((header::CACHE_CONTROL, cache_control))
+                .finish());
+        }
+        Precondition::Proceed => {}
+    }
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header(header::ETag(etag.clone()))
+        .insert_header((header::CACHE_CONTROL, cache_control))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    // Digests of the whole file, even in a partial response
+    for digest in crate::checksum::digest_headers(&file.hash) {
+        builder.insert_header(digest);
+    }
+    if let Some(filename) = &file.filename {
+        builder.insert_header(header::ContentDisposition {
+            disposition: file.disposition.clone(),
+            parameters: vec![header::DispositionParam::Filename(filename.clone())],
+        });
+    }
+    let is_head = req.method() == actix_web::http::Method::HEAD;
+
+    match requested_ranges(req, &etag, file.size) {
+        RangeRequest::Unsatisfiable => {
+            builder
+                .status(actix_web::http::StatusCode::RANGE_NOT_SATISFIABLE)
+                .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(file.size),
+                }));
+            Ok(builder.finish())
+        }
+        RangeRequest::Full => {
+            builder.content_type(file.content_type).no_chunking(file.size);
+            if is_head {
+                return Ok(builder.finish());
+            }
+            Ok(builder.streaming(storage.get(&file.key).await?))
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            builder
+                .status(actix_web::http::StatusCode::PARTIAL_CONTENT)
+                .content_type(file.content_type)
+                .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: Some((start, end)),
+                    instance_length: Some(file.size),
+                }))
+                .no_chunking(end - start + 1);
+            if is_head {
+                return Ok(builder.finish());
+            }
+            Ok(builder.streaming(storage.get_range(&file.key, start, end - start + 1).await?))
+        }
+        RangeRequest::Partial(ranges) => {
+            let boundary = uuid::Uuid::new_v4().simple().to_string();
+            let part_heads: Vec<String> = ranges.iter()
+                .map(|(start, end)| format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, file.content_type, start, end, file.size
+                ))
+                .collect();
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            let length = part_heads.iter().map(|h| h.len() as u64).sum::<u64>()
+                + ranges.iter().map(|(start, end)| end - start + 1).sum::<u64>()
+                + closing.len() as u64;
+            builder
+                .status(actix_web::http::StatusCode::PARTIAL_CONTENT)
+                .content_type(format!("multipart/byteranges; boundary={}", boundary))
+                .no_chunking(length);
+            if is_head {
+                return Ok(builder.finish());
+            }
+            let key = file.key;
+            let parts = stream::iter(part_heads.into_iter().zip(ranges).map(Ok))
+                .and_then(move |(head, (start, end))| {
+                    let storage = storage.clone();
+                    let key = key.clone();
+                    async move {
+                        let body = storage.get_range(&key, start, end - start + 1).await?;
+                        Ok::<_, std::io::Error>(storage::once(head.into()).chain(body))
+                    }
+                })
+                .try_flatten()
+                .chain(storage::once(closing.into()));
+            Ok(builder.streaming(parts))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_coalesce_ranges() {
+        assert_eq!(coalesce_ranges(vec![(10, 20), (0, 4), (5, 6), (15, 30), (40, 50)]), vec![(0, 6), (10, 30), (40, 50)]);
+    }
+
+    #[test]
+    fn test_requested_ranges() {
+        let etag = etag_for("abc");
+        let ranges = |value: &str| {
+            let req = TestRequest::default().insert_header((header::RANGE, value)).to_http_request();
+            requested_ranges(&req, &etag, 100)
+        };
+        assert_eq!(ranges("bytes=0-9"), RangeRequest::Partial(vec![(0, 9)]));
+        assert_eq!(ranges("bytes=-10, 0-4, 3-7"), RangeRequest::Partial(vec![(0, 7), (90, 99)]));
+        assert_eq!(ranges("bytes=200-"), RangeRequest::Unsatisfiable);
+        assert_eq!(ranges("lines=1-2"), RangeRequest::Full);
+        assert_eq!(ranges("bytes=oops"), RangeRequest::Full);
+
+        let stale = TestRequest::default()
+            .insert_header((header::RANGE, "bytes=0-9"))
+            .insert_header((header::IF_RANGE, "\"other\""))
+            .to_http_request();
+        assert_eq!(requested_ranges(&stale, &etag, 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_check_preconditions() {
+        let etag = etag_for("abc");
+        let check = |name, value: &str| check_preconditions(&TestRequest::default().insert_header((name, value)).to_http_request(), &etag);
+        assert_eq!(check(header::IF_NONE_MATCH, "\"abc\""), Precondition::NotModified);
+        assert_eq!(check(header::IF_NONE_MATCH, "W/\"abc\""), Precondition::NotModified);
+        assert_eq!(check(header::IF_NONE_MATCH, "\"xyz\""), Precondition::Proceed);
+        assert_eq!(check(header::IF_MATCH, "\"xyz\""), Precondition::Failed);
+        assert_eq!(check(header::IF_MATCH, "W/\"abc\""), Precondition::Failed);
+        assert_eq!(check(header::IF_MATCH, "*"), Precondition::Proceed);
+        assert_eq!(check_preconditions(&TestRequest::default().to_http_request(), &etag), Precondition::Proceed);
+    }
+}
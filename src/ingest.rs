@@ -0,0 +1,227 @@
+use crate::db_utils;
+use crate::file_utils::FileType;
+use crate::media_info::MediaInfo;
+use crate::storage;
+use crate::AppState;
+use std::time::{Duration, Instant};
+
+/// How long to wait for another writer, as rusqlite does by default
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A file that has been written to a temporary key and validated.
+#[derive(Debug, Clone)]
+pub struct Staged {
+    pub temp_key: String,
+    pub hash: String, // hex SHA-256
+    pub size: u64,
+    pub file_type: FileType,
+    pub media_info: Option<MediaInfo>,
+}
+
+/// Metadata of the logical file being created.
+#[derive(Debug, Clone, Default)]
+pub struct FileInfo {
+    pub uuid: String,
+    pub original_filename: Option<String>,
+    pub uploader: Option<String>,
+    pub tags: Vec<String>,
+    pub source_url: Option<String>,
+    pub public: bool,
+    pub expires_at: Option<String>, // "YYYY-MM-DD HH:MM:SS" UTC
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    pub id: i64,
+    pub uuid: String,
+    pub deduplicated: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("Storage error: {0}")]
+    Storage(#[from] std::io::Error),
+    #[error("Database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    #[error("Database pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+}
+
+impl actix_web::ResponseError for StoreError {}
+
+/// Store a staged file under its own ID. If identical content is already
+/// stored the file shares that blob, otherwise the temporary object becomes
+/// a new blob. The temporary object is gone afterwards either way.
+pub async fn store_file(state: &AppState, staged: &Staged, info: FileInfo) -> Result<StoredFile, StoreError> {
+    let result = store_staged(state, staged, info).await;
+    // Already moved if a new blob was created; deleting a missing key is fine
+    let _ = state.storage.delete(&staged.temp_key).await;
+    result
+}
+
+/// Store a staged file like `store_file`, but leave the staged object where
+/// it is if that fails, so the caller can try again. After success it has
+/// been moved into place, or is left for the caller to delete if the content
+/// was already stored.
+pub async fn store_staged(state: &AppState, staged: &Staged, info: FileInfo) -> Result<StoredFile, StoreError> {
+    // Claim the content before moving anything. Of concurrent uploads of the
+    // same bytes only the one whose blob row goes in moves its object into
+    // place; the others share that blob and their temporary objects are
+    // deleted. Nobody sees the new blob until the object is there.
+    let key = storage::sharded_key(&info.uuid, &staged.file_type.extension);
+    let tx = OwnedTransaction::begin(state.db_pool.get()?).await?;
+    let (blob, inserted) = db_utils::insert_or_get_blob(&tx, &staged.hash, &key, staged.size as i64)?;
+    if !inserted {
+        log::info!("Content {} already stored at {}", staged.hash, blob.filepath);
+    }
+    let deduplicated = !inserted;
+    let id = db_utils::insert_file(&tx, &db_utils::NewFile {
+        uuid: info.uuid.clone(),
+        blob_id: blob.id,
+        url: format!("/files/{}", info.uuid),
+        original_filename: info.original_filename,
+        mime_type: Some(staged.file_type.mime_type.clone()),
+        extension: Some(staged.file_type.extension.clone()),
+        uploader: info.uploader,
+        tags: info.tags,
+        deduplicated,
+        source_url: info.source_url,
+        media_info: staged.media_info.as_ref().and_then(|m| serde_json::to_string(m).ok()),
+        public: info.public,
+        expires_at: info.expires_at,
+    })?;
+    if inserted {
+        state.storage.rename(&staged.temp_key, &key).await?;
+    }
+    if let Err(e) = tx.commit() {
+        if inserted {
+            let _ = state.storage.rename(&key, &staged.temp_key).await;
+        }
+        return Err(e.into());
+    }
+    Ok(StoredFile { id, uuid: info.uuid, deduplicated })
+}
+
+/// Delete the file with public ID `uuid`, and its blob's stored object if no
+/// other file uses it. Returns `None` if there is no such file.
+pub async fn delete_file(state: &AppState, uuid: &str) -> Result<Option<db_utils::ReleasedFile>, StoreError> {
+    let tx = OwnedTransaction::begin(state.db_pool.get()?).await?;
+    let released = match db_utils::release_file(&tx, uuid)? {
+        Some(released) => released,
+        None => return Ok(None),
+    };
+    if released.remaining > 0 {
+        tx.commit()?;
+        return Ok(Some(released));
+    }
+    // Move the object aside before committing so either side can be
+    // undone; a crash in between leaves it in tmp/ to be swept at startup
+    let trash_key = storage::temp_key(&format!("{}.deleted", uuid));
+    state.storage.rename(&released.filepath, &trash_key).await?;
+    if let Err(e) = tx.commit() {
+        let _ = state.storage.rename(&trash_key, &released.filepath).await;
+        return Err(e.into());
+    }
+    if let Err(e) = state.storage.delete(&trash_key).await {
+        log::warn!("Failed to remove deleted file {}: {}", trash_key, e);
+    }
+    log::info!("Deleted file {} ({})", uuid, released.filepath);
+    Ok(Some(released))
+}
+
+/// A write transaction that owns its pooled connection and is rolled back
+/// unless committed. Unlike `rusqlite::Transaction` it doesn't borrow the
+/// connection, so it can be held across awaits in a future that must be
+/// `Send`, such as the worker's. It takes the write lock up front, so
+/// concurrent writers queue instead of failing to upgrade a read.
+struct OwnedTransaction {
+    conn: r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>,
+    finished: bool,
+}
+
+impl OwnedTransaction {
+    /// Wait for the write lock without blocking the thread, since the task
+    /// holding it may be waiting to run on this very thread.
+    async fn begin(conn: r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>) -> rusqlite::Result<Self> {
+        conn.busy_timeout(Duration::ZERO)?;
+        let deadline = Instant::now() + BUSY_TIMEOUT;
+        let begun = loop {
+            match conn.execute_batch("BEGIN IMMEDIATE") {
+                Err(rusqlite::Error::SqliteFailure(e, _))
+                    if e.code == rusqlite::ErrorCode::DatabaseBusy && Instant::now() < deadline =>
+                {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                result => break result,
+            }
+        };
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        begun?;
+        Ok(OwnedTransaction { conn, finished: false })
+    }
+
+    fn commit(mut self) -> rusqlite::Result<()> {
+        self.conn.execute_batch("COMMIT")?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl std::ops::Deref for OwnedTransaction {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &rusqlite::Connection {
+        &self.conn
+    }
+}
+
+impl Drop for OwnedTransaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.conn.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+/// Clean up client-supplied tags: trimmed, non-empty and without repeats.
+pub fn normalize_tags<I: IntoIterator<Item = S>, S: AsRef<str>>(tags: I) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.as_ref().trim();
+        if !tag.is_empty() && !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transactions_wait_without_blocking_the_thread() {
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let pool = r2d2::Pool::new(r2d2_sqlite::SqliteConnectionManager::file(db_file.path())).unwrap();
+        db_utils::init_db(&pool.get().unwrap()).unwrap();
+
+        // The second transaction can only begin once the first, on the
+        // same thread, gets to commit
+        let first = async {
+            let tx = OwnedTransaction::begin(pool.get().unwrap()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            tx.commit().unwrap();
+            Instant::now()
+        };
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let tx = OwnedTransaction::begin(pool.get().unwrap()).await.unwrap();
+            tx.commit().unwrap();
+            Instant::now()
+        };
+        let started = Instant::now();
+        let (first, second) = tokio::join!(first, second);
+        assert!(second > first);
+        assert!(started.elapsed() < BUSY_TIMEOUT);
+    }
+}
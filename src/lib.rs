@@ -1,32 +1,49 @@
+pub mod auth;
+pub mod checksum;
 mod config;
+pub mod cors;
 pub mod handlers;
 pub mod file_utils;
 pub mod multipart_utils;
 pub mod db_utils;
-use std::path::PathBuf;
+pub mod fetch;
+pub mod http_utils;
+pub mod ingest;
+pub mod media_info;
+pub mod signed_url;
+pub mod storage;
+pub mod tus;
 use std::sync::Arc;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-pub use config::Config;
+pub use config::{
+    AuthConfig, Config, ConfigError, CorsConfig, CorsPolicy, CorsRoute, FetchConfig, RetryPolicy, SignedUrlConfig,
+    SigningKey,
+};
 pub use handlers::{
-    serve_file, upload_file, download_file, get_job_status,
-    FileUploadResponse, DownloadResponse, about
+    serve_file, create_signed_url, get_file_metadata, list_files, update_file, delete_file, upload_file, put_file, put_file_with_id, download_file, get_job_status, cancel_job,
+    create_api_key, list_api_keys, revoke_api_key,
+    FileUploadResponse, FileUploadResult, FileMetadataResponse, FileListResponse, DownloadResponse, about
 };
 pub use worker::DownloadWorker;
+pub use storage::{StorageBackend, LocalStorage, MemoryStorage};
 
 pub mod worker;
 
 #[derive(Clone, Debug)]
 pub struct AppState {
-    pub media_path: PathBuf,
+    pub storage: Arc<dyn StorageBackend>,
     pub db_pool: Pool<SqliteConnectionManager>,
+    pub config: Config,
     pub worker: Option<Arc<DownloadWorker>>,
 }
 
-pub async fn create_app_state(media_path: PathBuf, db_pool: Pool<SqliteConnectionManager>, max_concurrent_downloads: usize) -> AppState {
+pub async fn create_app_state(storage: Arc<dyn StorageBackend>, db_pool: Pool<SqliteConnectionManager>, config: Config) -> AppState {
+    let max_concurrent_downloads = config.max_concurrent_downloads;
     let state = AppState {
-        media_path: media_path.clone(),
+        storage: storage.clone(),
         db_pool: db_pool.clone(),
+        config,
         worker: None,
     };
 
@@ -40,24 +57,57 @@ pub async fn create_app_state(media_path: PathBuf, db_pool: Pool<SqliteConnectio
     state_with_worker
 }
 
+/// Register the API with the default CORS policy
 pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
-    let cors = actix_cors::Cors::default()
-        .allow_any_origin()
-        .allow_any_method()
-        .allow_any_header()
-        .max_age(3600);
-    
+    config_with(&CorsConfig::default(), cfg);
+}
+
+/// Register the API with CORS as configured. Each CORS policy gets a scope
+/// holding every route, and a guard sends requests to the scope whose policy
+/// applies to them; the default policy's scope catches the rest.
+pub fn config_with(cors_config: &CorsConfig, cfg: &mut actix_web::web::ServiceConfig) {
+    for route in cors::ordered_routes(cors_config) {
+        let matcher = route.clone();
+        cfg.service(
+            api_services(actix_web::web::scope(""))
+                .guard(actix_web::guard::fn_guard(move |ctx| cors::route_matches(&matcher, ctx.head())))
+                .wrap(actix_web::middleware::from_fn(auth::authenticate))
+                .wrap(cors::build(&route.policy(&cors_config.default)))
+        );
+    }
     cfg.service(
-        actix_web::web::scope("")
-            .wrap(cors)
-            .service(handlers::upload_file)
-            .service(handlers::download_file)
-            .service(handlers::get_job_status)
-            .service(handlers::serve_file)
-            .service(handlers::about)
+        api_services(actix_web::web::scope(""))
+            .wrap(actix_web::middleware::from_fn(auth::authenticate))
+            .wrap(cors::build(&cors_config.default))
     );
 }
 
+fn api_services(scope: actix_web::Scope) -> actix_web::Scope {
+    scope
+        .service(handlers::upload_file)
+        .service(handlers::put_file)
+        .service(handlers::put_file_with_id)
+        .service(tus::tus_options)
+        .service(tus::create_upload)
+        .service(tus::upload_status)
+        .service(tus::append_upload)
+        .service(tus::terminate_upload)
+        .service(handlers::download_file)
+        .service(handlers::get_job_status)
+        .service(handlers::cancel_job)
+        .service(handlers::serve_file)
+        .service(handlers::serve_blob)
+        .service(handlers::create_signed_url)
+        .service(handlers::get_file_metadata)
+        .service(handlers::list_files)
+        .service(handlers::update_file)
+        .service(handlers::delete_file)
+        .service(handlers::create_api_key)
+        .service(handlers::list_api_keys)
+        .service(handlers::revoke_api_key)
+        .service(handlers::about)
+}
+
 pub fn routes(cfg: &mut actix_web::web::ServiceConfig) {
     config(cfg);
 }
\ No newline at end of file
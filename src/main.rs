@@ -1,51 +1,113 @@
 use actix_web::{web, App, HttpServer};
-use log;
-use r2d2;
-use stowage::{self, config, db_utils};
-use std::env;
-use std::path::PathBuf;
+use stowage::{self, auth, db_utils};
+use std::sync::Arc;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
-    let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
-    let port = env::var("PORT")
-        .unwrap_or_else(|_| "8080".to_string())
-        .parse::<u16>()
-        .expect("Invalid PORT value");
-    let media_path = env::var("MEDIA_PATH").unwrap_or_else(|_| "./media".to_string());
+    let app_config = match stowage::Config::load() {
+        Ok(app_config) => app_config,
+        Err(e) => {
+            log::error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let media_path = app_config.media_path.clone();
     std::fs::create_dir_all(&media_path)?;
-    let db_path = env::var("DB_PATH").unwrap_or_else(|_| "stowage.db".to_string());
-    let manager = r2d2_sqlite::SqliteConnectionManager::file(&db_path);
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(&app_config.db_path);
     let db_pool = r2d2::Pool::new(manager).expect("Failed to create DB pool");
     {
         let conn = db_pool.get().expect("Failed to get DB connection");
         db_utils::init_db(&conn).expect("Failed to initialize DB");
+        let root = std::fs::canonicalize(&media_path)?;
+        let mut migrated = 0;
+        for prefix in [media_path.to_string_lossy().into_owned(), root.to_string_lossy().into_owned()] {
+            migrated += db_utils::relativize_filepaths(&conn, &prefix)
+                .expect("Failed to migrate file paths");
+        }
+        if migrated > 0 {
+            log::info!("Migrated {} file paths to storage keys", migrated);
+        }
     }
-    // Get max concurrent downloads from env or use a default of 5
-    let max_concurrent_downloads = env::var("MAX_CONCURRENT_DOWNLOADS")
-        .unwrap_or_else(|_| "5".to_string())
-        .parse::<usize>()
-        .expect("Invalid MAX_CONCURRENT_DOWNLOADS value");
 
+    // `stowage keys ...` manages API keys instead of starting the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("keys") {
+        let conn = db_pool.get().expect("Failed to get DB connection");
+        if let Err(e) = manage_keys(&conn, &args[1..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let host = app_config.host.clone();
+    let port = app_config.port;
     log::info!("Starting server on {}:{}", host, port);
-    log::info!("Serving files from: {}", media_path);
-    log::info!("Max concurrent downloads: {}", max_concurrent_downloads);
+    log::info!("Serving files from: {}", media_path.display());
+    log::info!("Max concurrent downloads: {}", app_config.max_concurrent_downloads);
+    log::info!("Max file size: {} bytes", app_config.max_file_size);
+    if !app_config.auth.enabled {
+        let warning = "WARNING: API keys are disabled, so anyone who can reach this server can upload, \
+            read and delete files. Mint an admin key with `stowage keys create <name> admin` and set \
+            AUTH_ENABLED=true (or `enabled = true` under [auth]).";
+        log::warn!("{}", warning);
+        eprintln!("{}", warning);
+    }
 
     // Create app state with worker and start the worker
+    let storage = Arc::new(stowage::LocalStorage::new(&media_path));
     let app_state = stowage::create_app_state(
-        PathBuf::from(&media_path),
+        storage,
         db_pool.clone(),
-        max_concurrent_downloads,
+        app_config,
     ).await;
 
     // Start the HTTP server
     HttpServer::new(move || {
+        let cors = app_state.config.cors.clone();
         App::new()
             .app_data(web::Data::new(app_state.clone()))
-            .configure(config)
+            .configure(move |cfg| stowage::config_with(&cors, cfg))
     })
     .bind((host, port))?
     .run()
     .await
-}
\ No newline at end of file
+}
+
+const KEYS_USAGE: &str = "Usage:
+  stowage keys create <name> <scope>[,<scope>...]   scopes: upload, read, download-job, admin
+  stowage keys list
+  stowage keys revoke <id>";
+
+fn manage_keys(conn: &rusqlite::Connection, args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["create", name, scopes] => {
+            let scopes = scopes.split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<auth::Scope>, String>>()?;
+            let (id, key) = auth::mint_key(conn, name, &scopes).map_err(|e| e.to_string())?;
+            println!("Created key {} ({}). It will not be shown again:\n{}", id, name, key);
+        }
+        ["list"] => {
+            for key in db_utils::list_api_keys(conn).map_err(|e| e.to_string())? {
+                let state = match &key.revoked_at {
+                    Some(at) => format!("revoked {}", at),
+                    None => format!("last used {}", key.last_used_at.as_deref().unwrap_or("never")),
+                };
+                println!("{}\t{}\t{}...\t{}\t{}", key.id, key.name, key.prefix, key.scopes.join(","), state);
+            }
+        }
+        ["revoke", id] => {
+            let id: i64 = id.parse().map_err(|_| format!("Invalid key ID {:?}", id))?;
+            if !db_utils::revoke_api_key(conn, id).map_err(|e| e.to_string())? {
+                return Err(format!("No active key with ID {}", id));
+            }
+            println!("Revoked key {}", id);
+        }
+        _ => return Err(KEYS_USAGE.to_string()),
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Properties of a file read from its first bytes, shown by `GET /files/{id}/meta`.
+
+/// What could be learned about a file's content. Only image dimensions are
+/// extracted for now; fields that aren't known are left out of the JSON.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MediaInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+/// Extract media info from the head of a file of the given MIME type.
+/// Returns `None` if the format isn't supported or the head is too short.
+pub fn extract(head: &[u8], mime_type: &str) -> Option<MediaInfo> {
+    let (width, height) = match mime_type {
+        "image/png" => png_size(head)?,
+        "image/gif" => gif_size(head)?,
+        "image/jpeg" => jpeg_size(head)?,
+        "image/webp" => webp_size(head)?,
+        _ => return None,
+    };
+    Some(MediaInfo { width: Some(width), height: Some(height) })
+}
+
+fn be16(b: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_be_bytes(b.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn le16(b: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_le_bytes(b.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn le24(b: &[u8], at: usize) -> Option<u32> {
+    let b = b.get(at..at + 3)?;
+    Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+}
+
+// IHDR is always the first chunk
+fn png_size(b: &[u8]) -> Option<(u32, u32)> {
+    if b.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(b.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(b.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+// Logical screen size from the header
+fn gif_size(b: &[u8]) -> Option<(u32, u32)> {
+    Some((le16(b, 6)?, le16(b, 8)?))
+}
+
+// Walk the segments up to the first start-of-frame marker
+fn jpeg_size(b: &[u8]) -> Option<(u32, u32)> {
+    let mut at = 2;
+    loop {
+        if *b.get(at)? != 0xFF {
+            return None;
+        }
+        let marker = *b.get(at + 1)?;
+        match marker {
+            0xFF => at += 1, // fill byte
+            0xD8 | 0x01 | 0xD0..=0xD7 => at += 2, // markers without a length
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                return Some((be16(b, at + 7)?, be16(b, at + 5)?));
+            }
+            _ => at += 2 + be16(b, at + 2)? as usize,
+        }
+    }
+}
+
+fn webp_size(b: &[u8]) -> Option<(u32, u32)> {
+    if b.get(0..4)? != b"RIFF" || b.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    match b.get(12..16)? {
+        b"VP8X" => Some((le24(b, 24)? + 1, le24(b, 27)? + 1)),
+        b"VP8 " => Some((le16(b, 26)? & 0x3FFF, le16(b, 28)? & 0x3FFF)),
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(b.get(21..25)?.try_into().ok()?);
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_image_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        assert_eq!(extract(&png, "image/png"), Some(MediaInfo { width: Some(640), height: Some(480) }));
+
+        let gif = b"GIF89a\x20\x00\x10\x00";
+        assert_eq!(extract(gif, "image/gif"), Some(MediaInfo { width: Some(32), height: Some(16) }));
+
+        // SOI, an APP0 segment, then a baseline SOF0 frame of 3x2
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0x02, 0x00, 0x03];
+        assert_eq!(extract(&jpeg, "image/jpeg"), Some(MediaInfo { width: Some(3), height: Some(2) }));
+
+        assert_eq!(extract(&png[..20], "image/png"), None);
+        assert_eq!(extract(b"{}", "application/json"), None);
+    }
+}
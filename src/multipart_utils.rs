@@ -1,21 +1,87 @@
 use actix_multipart::Field;
-use actix_web::{web, Error};
-use futures_util::stream::StreamExt;
-use std::io::Write;
+use actix_web::Error;
+use bytes::Bytes;
+use futures_util::{SinkExt, Stream, StreamExt};
+use crate::file_utils::{as_payload_too_large, Inspected, StreamInspector};
+use crate::storage::StorageBackend;
 
 pub fn get_filename_from_field(field: &Field) -> String {
     let content_disposition = field.content_disposition();
     content_disposition.get_filename().unwrap_or("file").to_string()
 }
 
-pub async fn write_temp_file(mut field: Field, temp_path: &std::path::Path) -> Result<(), Error> {
-    let temp_path_clone = temp_path.to_path_buf();
-    let mut file = web::block(move || std::fs::File::create(&temp_path_clone)).await
-        .map_err(|e| actix_web::error::ErrorBadRequest(format!("File create error: {:?}", e)))??;
-    while let Some(chunk) = field.next().await {
-        let chunk = chunk.map_err(|e| actix_web::error::ErrorBadRequest(format!("Chunk error: {}", e)))?;
-        file = web::block(move || file.write_all(&chunk).map(|_| file)).await
-            .map_err(|e| actix_web::error::ErrorBadRequest(format!("Write error: {:?}", e)))??;
+/// Stream a multipart field, or any other request body, into storage under
+/// `key`, passing each chunk through `inspector` on the way. The body is
+/// only read once: its size, hash and head are known when it has been
+/// written, without reading it back.
+///
+/// `Field` and `Payload` are tied to the request's thread, so chunks are
+/// forwarded through a channel to the backend rather than handing it the
+/// body itself; the backend does its IO off the actix worker threads. The
+/// upload is aborted with 413 as soon as the inspector's size limit rejects it.
+pub async fn write_temp_file<S, E>(
+    mut body: S,
+    storage: &dyn StorageBackend,
+    key: &str,
+    mut inspector: StreamInspector,
+) -> Result<Inspected, Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let (mut tx, rx) = futures::channel::mpsc::channel::<std::io::Result<Bytes>>(8);
+    let forward = async move {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk
+                .map_err(|e| std::io::Error::other(format!("Chunk error: {}", e)))
+                .and_then(|chunk| inspector.update(&chunk).map(|_| chunk).map_err(std::io::Error::other));
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                return None;
+            }
+        }
+        match inspector.finish() {
+            Ok(inspected) => Some(inspected),
+            Err(e) => {
+                let _ = tx.send(Err(std::io::Error::other(e))).await;
+                None
+            }
+        }
+    };
+    let (inspected, written) = futures::join!(forward, storage.put(key, rx.boxed()));
+    written.map_err(|e| match as_payload_too_large(&e) {
+        Some(too_large) => actix_web::error::ErrorPayloadTooLarge(too_large.to_string()),
+        None => actix_web::error::ErrorBadRequest(format!("Write error: {}", e)),
+    })?;
+    inspected.ok_or_else(|| actix_web::error::ErrorBadRequest("Upload ended early"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use futures_util::stream;
+
+    fn body(chunks: &[&'static [u8]]) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Unpin {
+        stream::iter(chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk))).collect::<Vec<_>>())
+    }
+
+    #[actix_web::test]
+    async fn test_write_temp_file_inspects_while_writing() {
+        let storage = MemoryStorage::new();
+        let config = crate::Config::default();
+        let inspected = write_temp_file(body(&[b"{\"a\":", b" 1}"]), &storage, "tmp/a.tmp", StreamInspector::new(&config, "a.json"))
+            .await
+            .unwrap();
+        assert_eq!(inspected.size, 8);
+        assert_eq!(inspected.head, b"{\"a\": 1}");
+        assert_eq!(inspected.hash, "f9d86028c6e0d64e225186f96acb69338b2c59764df79162107f5c4bb34d1310");
+        assert_eq!(storage.stat("tmp/a.tmp").await.unwrap().unwrap().size, 8);
+
+        let config = crate::Config { max_file_size: 4, max_file_size_by_type: Default::default(), ..Default::default() };
+        let err = write_temp_file(body(&[b"{\"a\":", b" 1}"]), &storage, "tmp/b.tmp", StreamInspector::new(&config, "b.json"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), actix_web::http::StatusCode::PAYLOAD_TOO_LARGE);
     }
-    Ok(())
 }
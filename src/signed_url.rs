@@ -0,0 +1,154 @@
+//! HMAC-signed, expiring links to files, usable without an API key.
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+
+use crate::config::SigningKey;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How a signed link asks the browser to treat the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposition {
+    Inline,
+    Attachment,
+}
+
+/// What a signed URL grants: reading one file until `expires`, optionally
+/// only from one IP and with a fixed Content-Disposition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub file_id: String,
+    pub expires: i64, // Unix seconds
+    pub ip: Option<IpAddr>,
+    pub disposition: Option<Disposition>,
+    pub filename: Option<String>,
+}
+
+/// Query parameters of a signed URL. All absent on a normal request.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SignedQuery {
+    pub expires: Option<i64>,
+    pub kid: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub disposition: Option<Disposition>,
+    pub filename: Option<String>,
+    pub sig: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignatureError {
+    #[error("Signed URL is incomplete")]
+    Malformed,
+    #[error("Signed URL was signed with an unknown key")]
+    UnknownKey,
+    #[error("Signed URL has an invalid signature")]
+    BadSignature,
+    #[error("Signed URL has expired")]
+    Expired,
+    #[error("Signed URL is not valid from this address")]
+    WrongIp,
+}
+
+impl actix_web::ResponseError for SignatureError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::FORBIDDEN
+    }
+}
+
+// Serialized as JSON so no combination of field values can collide
+fn mac(key: &SigningKey, grant: &Grant) -> HmacSha256 {
+    let message = serde_json::to_vec(&(
+        &grant.file_id,
+        grant.expires,
+        grant.ip,
+        grant.disposition,
+        &grant.filename,
+    )).unwrap_or_default();
+    let mut mac = HmacSha256::new_from_slice(key.secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(&message);
+    mac
+}
+
+/// Path and query of a URL for `grant`, signed with `key`.
+pub fn sign(key: &SigningKey, grant: &Grant) -> String {
+    let sig = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(mac(key, grant).finalize().into_bytes());
+    let query = serde_urlencoded::to_string(SignedQuery {
+        expires: Some(grant.expires),
+        kid: Some(key.id.clone()),
+        ip: grant.ip,
+        disposition: grant.disposition,
+        filename: grant.filename.clone(),
+        sig: Some(sig),
+    }).unwrap_or_default();
+    format!("/files/{}?{}", grant.file_id, query)
+}
+
+/// Check the signed query of a request for `file_id` made from `client_ip`
+/// at `now` (Unix seconds), trying the key the URL names among `keys`.
+pub fn verify(keys: &[SigningKey], file_id: &str, query: &SignedQuery, client_ip: Option<IpAddr>, now: i64) -> Result<Grant, SignatureError> {
+    let (Some(expires), Some(kid), Some(sig)) = (query.expires, &query.kid, &query.sig) else {
+        return Err(SignatureError::Malformed);
+    };
+    let key = keys.iter().find(|k| &k.id == kid).ok_or(SignatureError::UnknownKey)?;
+    let grant = Grant {
+        file_id: file_id.to_string(),
+        expires,
+        ip: query.ip,
+        disposition: query.disposition,
+        filename: query.filename.clone(),
+    };
+    let sig = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(sig).map_err(|_| SignatureError::BadSignature)?;
+    mac(key, &grant).verify_slice(&sig).map_err(|_| SignatureError::BadSignature)?;
+    if now >= expires {
+        return Err(SignatureError::Expired);
+    }
+    if grant.ip.is_some() && grant.ip != client_ip {
+        return Err(SignatureError::WrongIp);
+    }
+    Ok(grant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str) -> SigningKey {
+        SigningKey { id: id.into(), secret: format!("{}-0123456789abcdef0123456789abcdef", id) }
+    }
+
+    fn query_of(url: &str) -> SignedQuery {
+        serde_urlencoded::from_str(url.split_once('?').unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let grant = Grant {
+            file_id: "abc".into(),
+            expires: 1000,
+            ip: Some("10.0.0.1".parse().unwrap()),
+            disposition: Some(Disposition::Attachment),
+            filename: Some("a b&c.mp3".into()),
+        };
+        let url = sign(&key("old"), &grant);
+        assert!(url.starts_with("/files/abc?"));
+        let query = query_of(&url);
+        let ip = grant.ip;
+
+        // Still accepted once a new key signs, until the old one is removed
+        let keys = [key("new"), key("old")];
+        assert_eq!(verify(&keys, "abc", &query, ip, 999).unwrap(), grant);
+        assert!(matches!(verify(&keys[..1], "abc", &query, ip, 999), Err(SignatureError::UnknownKey)));
+        assert!(matches!(verify(&keys, "abc", &query, ip, 1000), Err(SignatureError::Expired)));
+        assert!(matches!(verify(&keys, "abc", &query, Some("10.0.0.2".parse().unwrap()), 999), Err(SignatureError::WrongIp)));
+        assert!(matches!(verify(&keys, "abd", &query, ip, 999), Err(SignatureError::BadSignature)));
+        let tampered = SignedQuery { expires: Some(5000), ..query.clone() };
+        assert!(matches!(verify(&keys, "abc", &tampered, ip, 999), Err(SignatureError::BadSignature)));
+        let unbound = SignedQuery { ip: None, ..query };
+        assert!(matches!(verify(&keys, "abc", &unbound, None, 999), Err(SignatureError::BadSignature)));
+        assert!(matches!(verify(&keys, "abc", &SignedQuery::default(), ip, 999), Err(SignatureError::Malformed)));
+    }
+}
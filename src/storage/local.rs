@@ -0,0 +1,177 @@
+// src/storage/local.rs
+use super::{check_key, ByteStream, ObjectMeta, StorageBackend};
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+/// Stores objects as plain files under a root directory (the `MEDIA_PATH`).
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path_for(&self, key: &str) -> io::Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+
+    async fn ensure_parent(path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(&self, key: &str, mut data: ByteStream) -> io::Result<u64> {
+        let path = self.path_for(key)?;
+        Self::ensure_parent(&path).await?;
+        let mut file = tokio::fs::File::create(&path).await?;
+        let mut written = 0u64;
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
+    }
+
+    async fn append(&self, key: &str, mut data: ByteStream) -> io::Result<u64> {
+        let path = self.path_for(key)?;
+        Self::ensure_parent(&path).await?;
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+        let mut written = 0u64;
+        while let Some(chunk) = data.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    file.flush().await?;
+                    return Err(e);
+                }
+            };
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
+    }
+
+    async fn get(&self, key: &str) -> io::Result<ByteStream> {
+        let file = tokio::fs::File::open(self.path_for(key)?).await?;
+        Ok(ReaderStream::new(file).boxed())
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> io::Result<ByteStream> {
+        let mut file = tokio::fs::File::open(self.path_for(key)?).await?;
+        file.seek(io::SeekFrom::Start(start)).await?;
+        Ok(ReaderStream::new(file.take(len)).boxed())
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectMeta>> {
+        match tokio::fs::metadata(self.path_for(key)?).await {
+            Ok(meta) if meta.is_file() => Ok(Some(ObjectMeta {
+                key: key.to_string(),
+                size: meta.len(),
+                modified: meta.modified().ok(),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path_for(key)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<BoxStream<'static, io::Result<ObjectMeta>>> {
+        let root = self.root.clone();
+        // Only the directory the prefix names can hold matches, so start
+        // there rather than walking everything stored
+        let start = match prefix.rfind('/') {
+            Some(end) => self.path_for(&prefix[..end])?,
+            None => root.clone(),
+        };
+        let prefix = prefix.to_string();
+        // Walk the tree lazily: the state is the stack of directories still to
+        // visit plus the entries of the directory currently being read. A
+        // missing directory is just empty.
+        let walk = stream::unfold(
+            (vec![start], None::<tokio::fs::ReadDir>),
+            move |(mut pending, mut current)| {
+                let root = root.clone();
+                async move {
+                    loop {
+                        let dir = match current.as_mut() {
+                            Some(dir) => dir,
+                            None => {
+                                let next = pending.pop()?;
+                                match tokio::fs::read_dir(&next).await {
+                                    Ok(dir) => current = Some(dir),
+                                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                                    Err(e) => return Some((Err(e), (pending, None))),
+                                }
+                                continue;
+                            }
+                        };
+                        let entry = match dir.next_entry().await {
+                            Ok(Some(entry)) => entry,
+                            Ok(None) => {
+                                current = None;
+                                continue;
+                            }
+                            Err(e) => return Some((Err(e), (pending, None))),
+                        };
+                        let meta = match entry.metadata().await {
+                            Ok(meta) => meta,
+                            Err(e) => return Some((Err(e), (pending, current))),
+                        };
+                        if meta.is_dir() {
+                            pending.push(entry.path());
+                            continue;
+                        }
+                        let key = entry.path().strip_prefix(&root)
+                            .map(|p| p.to_string_lossy().replace('\\', "/"))
+                            .unwrap_or_default();
+                        let item = ObjectMeta { key, size: meta.len(), modified: meta.modified().ok() };
+                        return Some((Ok(item), (pending, current)));
+                    }
+                }
+            },
+        );
+        Ok(walk
+            .filter(move |item| {
+                let keep = match item {
+                    Ok(meta) => meta.key.starts_with(&prefix),
+                    Err(_) => true,
+                };
+                async move { keep }
+            })
+            .boxed())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let from = self.path_for(from)?;
+        let to = self.path_for(to)?;
+        Self::ensure_parent(&to).await?;
+        tokio::fs::rename(from, to).await
+    }
+}
@@ -0,0 +1,115 @@
+// src/storage/memory.rs
+use super::{check_key, once, ByteStream, ObjectMeta, StorageBackend};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// Keeps every object in memory. Intended for tests and throwaway instances.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: RwLock<BTreeMap<String, (Bytes, SystemTime)>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn not_found(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: {}", key))
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("Memory storage lock poisoned")
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn put(&self, key: &str, mut data: ByteStream) -> io::Result<u64> {
+        check_key(key)?;
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = data.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        let len = buf.len() as u64;
+        self.objects.write().map_err(|_| poisoned())?
+            .insert(key.to_string(), (buf.freeze(), SystemTime::now()));
+        Ok(len)
+    }
+
+    async fn append(&self, key: &str, mut data: ByteStream) -> io::Result<u64> {
+        check_key(key)?;
+        let mut buf = BytesMut::new();
+        let mut result = Ok(());
+        while let Some(chunk) = data.next().await {
+            match chunk {
+                Ok(chunk) => buf.extend_from_slice(&chunk),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let len = buf.len() as u64;
+        let mut objects = self.objects.write().map_err(|_| poisoned())?;
+        let existing = objects.remove(key).map(|(data, _)| data).unwrap_or_default();
+        let mut combined = BytesMut::from(&existing[..]);
+        combined.extend_from_slice(&buf);
+        objects.insert(key.to_string(), (combined.freeze(), SystemTime::now()));
+        result.map(|_| len)
+    }
+
+    async fn get(&self, key: &str) -> io::Result<ByteStream> {
+        let objects = self.objects.read().map_err(|_| poisoned())?;
+        let (data, _) = objects.get(key).ok_or_else(|| not_found(key))?;
+        Ok(once(data.clone()))
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> io::Result<ByteStream> {
+        let objects = self.objects.read().map_err(|_| poisoned())?;
+        let (data, _) = objects.get(key).ok_or_else(|| not_found(key))?;
+        let start = (start as usize).min(data.len());
+        let end = start.saturating_add(len as usize).min(data.len());
+        Ok(once(data.slice(start..end)))
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectMeta>> {
+        let objects = self.objects.read().map_err(|_| poisoned())?;
+        Ok(objects.get(key).map(|(data, modified)| ObjectMeta {
+            key: key.to_string(),
+            size: data.len() as u64,
+            modified: Some(*modified),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.objects.write().map_err(|_| poisoned())?.remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<BoxStream<'static, io::Result<ObjectMeta>>> {
+        let objects = self.objects.read().map_err(|_| poisoned())?;
+        let items: Vec<io::Result<ObjectMeta>> = objects.iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (data, modified))| Ok(ObjectMeta {
+                key: key.clone(),
+                size: data.len() as u64,
+                modified: Some(*modified),
+            }))
+            .collect();
+        Ok(stream::iter(items).boxed())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        check_key(to)?;
+        let mut objects = self.objects.write().map_err(|_| poisoned())?;
+        let object = objects.remove(from).ok_or_else(|| not_found(from))?;
+        objects.insert(to.to_string(), object);
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+// src/storage/mod.rs
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures_util::future;
+use futures_util::stream::{BoxStream, StreamExt, TryStreamExt};
+use std::io;
+use std::time::SystemTime;
+
+mod local;
+mod memory;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+
+/// A stream of byte chunks flowing into or out of a storage backend.
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Metadata about a single stored object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Where file bytes live. Keys are `/`-separated relative paths such as
+/// `12/3e/123e4567-e89b-12d3-a456-426614174000.json`; handlers never touch the
+/// filesystem directly and only talk to the backend through this trait.
+#[async_trait]
+pub trait StorageBackend: Send + Sync + std::fmt::Debug {
+    /// Store the whole stream under `key`, replacing any existing object.
+    /// Returns the number of bytes written.
+    async fn put(&self, key: &str, data: ByteStream) -> io::Result<u64>;
+
+    /// Add the stream to the end of the object under `key`, creating it if
+    /// needed. Returns the number of bytes appended. The default copies the
+    /// object to a new key with the data added and moves it back; backends
+    /// that can append in place should override it, keeping whatever arrived
+    /// before an error in the stream.
+    async fn append(&self, key: &str, data: ByteStream) -> io::Result<u64> {
+        let (existing, before) = match self.stat(key).await? {
+            Some(meta) => (self.get(key).await?, meta.size),
+            None => (futures_util::stream::empty().boxed(), 0),
+        };
+        let staging = format!("{}.append", key);
+        match self.put(&staging, existing.chain(data).boxed()).await {
+            Ok(written) => {
+                self.rename(&staging, key).await?;
+                Ok(written - before)
+            }
+            Err(e) => {
+                let _ = self.delete(&staging).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Stream the object stored under `key`.
+    async fn get(&self, key: &str) -> io::Result<ByteStream>;
+
+    /// Stream `len` bytes of the object stored under `key`, starting at
+    /// `start`. The default reads and discards everything before `start`;
+    /// backends that can seek should override it.
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> io::Result<ByteStream> {
+        Ok(slice_stream(self.get(key).await?, start, len))
+    }
+
+    /// Metadata for `key`, or `None` if it does not exist.
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectMeta>>;
+
+    /// Remove `key`. Removing a missing key is not an error.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Stream metadata for every object whose key starts with `prefix`.
+    async fn list(&self, prefix: &str) -> io::Result<BoxStream<'static, io::Result<ObjectMeta>>>;
+
+    /// Move an object to a new key, replacing any existing object there.
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+}
+
+/// Read up to `limit` bytes from the start of an object, e.g. for content sniffing.
+pub async fn read_head(storage: &dyn StorageBackend, key: &str, limit: usize) -> io::Result<Vec<u8>> {
+    let mut stream = storage.get(key).await?;
+    let mut head = BytesMut::with_capacity(limit);
+    while head.len() < limit {
+        match stream.next().await {
+            Some(chunk) => {
+                let chunk = chunk?;
+                let take = chunk.len().min(limit - head.len());
+                head.extend_from_slice(&chunk[..take]);
+            }
+            None => break,
+        }
+    }
+    Ok(head.to_vec())
+}
+
+/// Cut `len` bytes starting at `start` out of `stream`.
+pub fn slice_stream(stream: ByteStream, start: u64, len: u64) -> ByteStream {
+    let end = start.saturating_add(len);
+    stream
+        .scan(0u64, move |pos, chunk| {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => return future::ready(Some(Err(e))),
+            };
+            let from = *pos;
+            *pos += chunk.len() as u64;
+            if from >= end {
+                return future::ready(None);
+            }
+            let lo = start.saturating_sub(from).min(chunk.len() as u64) as usize;
+            let hi = (end - from).min(chunk.len() as u64) as usize;
+            future::ready(Some(Ok(chunk.slice(lo..hi))))
+        })
+        .try_filter(|chunk| future::ready(!chunk.is_empty()))
+        .boxed()
+}
+
+/// Wrap an in-memory buffer as a single-chunk `ByteStream`.
+pub fn once(data: Bytes) -> ByteStream {
+    futures_util::stream::once(async move { Ok(data) }).boxed()
+}
+
+/// Key for a stored file, sharded on the first four hex digits of its ID
+/// (`ab/cd/abcd1234-....ext`) so no single directory grows too large.
+pub fn sharded_key(id: &str, extension: &str) -> String {
+    let hex: String = id.chars().filter(|c| c.is_ascii_alphanumeric()).take(4).collect();
+    let name = if extension.is_empty() { id.to_string() } else { format!("{}.{}", id, extension) };
+    if hex.len() < 4 {
+        return name;
+    }
+    format!("{}/{}/{}", &hex[..2], &hex[2..], name)
+}
+
+/// Key for a file that is still being written or validated.
+pub fn temp_key(id: &str) -> String {
+    format!("tmp/{}.tmp", id)
+}
+
+/// Key for the data received so far of a resumable upload. Kept out of
+/// `tmp/`, which is cleared at startup, so uploads survive a restart.
+pub fn upload_key(id: &str) -> String {
+    format!("uploads/{}.part", id)
+}
+
+/// Reject keys that could escape the storage root.
+pub(crate) fn check_key(key: &str) -> io::Result<()> {
+    let bad = key.is_empty()
+        || key.starts_with('/')
+        || key.contains('\\')
+        || key.split('/').any(|part| part.is_empty() || part == "." || part == "..");
+    if bad {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid storage key: {:?}", key)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn collect(stream: ByteStream) -> Vec<u8> {
+        let chunks: Vec<_> = stream.collect().await;
+        chunks.into_iter().flat_map(|c| c.unwrap().to_vec()).collect()
+    }
+
+    async fn exercise_backend(storage: &dyn StorageBackend) {
+        let chunks = vec![Ok(Bytes::from_static(b"hello ")), Ok(Bytes::from_static(b"world"))];
+        let written = storage.put("a/one.txt", futures_util::stream::iter(chunks).boxed()).await.unwrap();
+        assert_eq!(written, 11);
+        storage.put("two.txt", once(Bytes::from_static(b"2"))).await.unwrap();
+
+        assert_eq!(collect(storage.get("a/one.txt").await.unwrap()).await, b"hello world");
+        assert_eq!(read_head(storage, "a/one.txt", 5).await.unwrap(), b"hello");
+        assert_eq!(collect(storage.get_range("a/one.txt", 4, 3).await.unwrap()).await, b"o w");
+        assert_eq!(collect(storage.get_range("a/one.txt", 6, 100).await.unwrap()).await, b"world");
+        assert_eq!(storage.stat("a/one.txt").await.unwrap().unwrap().size, 11);
+        assert!(storage.stat("missing").await.unwrap().is_none());
+
+        let mut keys: Vec<String> = storage.list("").await.unwrap()
+            .map(|m| m.unwrap().key)
+            .collect().await;
+        keys.sort();
+        assert_eq!(keys, vec!["a/one.txt".to_string(), "two.txt".to_string()]);
+        for (prefix, expected) in [("a/", vec!["a/one.txt"]), ("a/o", vec!["a/one.txt"]), ("t", vec!["two.txt"]), ("missing/", vec![])] {
+            let keys: Vec<String> = storage.list(prefix).await.unwrap().map(|m| m.unwrap().key).collect().await;
+            assert_eq!(keys, expected, "{}", prefix);
+        }
+
+        assert_eq!(storage.append("a/one.txt", once(Bytes::from_static(b"!"))).await.unwrap(), 1);
+        assert_eq!(storage.append("new.txt", once(Bytes::from_static(b"new"))).await.unwrap(), 3);
+        let failing = futures_util::stream::iter(vec![Ok(Bytes::from_static(b"?")), Err(io::Error::other("dropped"))]);
+        assert!(storage.append("a/one.txt", failing.boxed()).await.is_err());
+        assert_eq!(collect(storage.get("a/one.txt").await.unwrap()).await, b"hello world!?");
+        assert_eq!(collect(storage.get("new.txt").await.unwrap()).await, b"new");
+        storage.delete("new.txt").await.unwrap();
+        storage.put("a/one.txt", once(Bytes::from_static(b"hello world"))).await.unwrap();
+
+        storage.rename("a/one.txt", "b/moved.txt").await.unwrap();
+        assert!(storage.stat("a/one.txt").await.unwrap().is_none());
+        assert_eq!(collect(storage.get("b/moved.txt").await.unwrap()).await, b"hello world");
+
+        storage.delete("b/moved.txt").await.unwrap();
+        storage.delete("b/moved.txt").await.unwrap();
+        assert!(storage.get("b/moved.txt").await.is_err());
+        assert!(storage.put("../escape", once(Bytes::new())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slice_stream_across_chunks() {
+        let chunks = ["abc", "def", "ghi"].map(|c| Ok(Bytes::from_static(c.as_bytes())));
+        let sliced = slice_stream(futures_util::stream::iter(chunks).boxed(), 2, 5);
+        assert_eq!(collect(sliced).await, b"cdefg");
+    }
+
+    #[test]
+    fn test_sharded_key() {
+        assert_eq!(sharded_key("123e4567-e89b", "json"), "12/3e/123e4567-e89b.json");
+        assert_eq!(sharded_key("123e4567-e89b", ""), "12/3e/123e4567-e89b");
+        assert_eq!(sharded_key("ab", "mp3"), "ab.mp3");
+    }
+
+    #[tokio::test]
+    async fn test_local_storage_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise_backend(&LocalStorage::new(dir.path())).await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage_roundtrip() {
+        exercise_backend(&MemoryStorage::new()).await;
+    }
+}
@@ -0,0 +1,480 @@
+//! Resumable uploads speaking tus 1.0.0 (https://tus.io): the core protocol
+//! plus the creation, creation-with-upload, expiration and termination
+//! extensions. An upload's data is appended under `storage::upload_key`
+//! until complete, then stored like any other upload under the upload's ID.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{delete, patch, post, route, web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use base64::Engine;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use uuid::Uuid;
+
+use crate::auth::{self, Scope};
+use crate::db_utils::{self, UploadRecord};
+use crate::file_utils::{validate_file_type, StreamInspector};
+use crate::storage::{self, StorageBackend};
+use crate::{ingest, media_info, AppState};
+
+pub const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,creation-with-upload,expiration,termination";
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TusError {
+    #[error("Unsupported Tus-Resumable version; this server speaks {}", TUS_VERSION)]
+    Version,
+    #[error("Missing or invalid {0} header")]
+    BadHeader(&'static str),
+    #[error("Content-Type must be {}", OFFSET_OCTET_STREAM)]
+    ContentType,
+    #[error("Upload-Offset {given} does not match the {received} bytes received")]
+    Offset { given: u64, received: u64 },
+    #[error("Upload-Length exceeds the {0} byte limit")]
+    TooLarge(u64),
+    #[error("Request body runs past Upload-Length")]
+    Overflow,
+    #[error("Request body broke off: {0}")]
+    Body(String),
+    #[error("Upload not found")]
+    NotFound,
+    #[error("Upload has expired")]
+    Expired,
+    #[error("Upload is being written by another request")]
+    Locked,
+    #[error("Storage error: {0}")]
+    Storage(#[from] std::io::Error),
+    #[error("Database error: {0}")]
+    Db(String),
+    // Errors of the shared upload pipeline, e.g. a disallowed file type
+    #[error("{0}")]
+    Rejected(actix_web::Error),
+}
+
+impl actix_web::ResponseError for TusError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TusError::Version => StatusCode::PRECONDITION_FAILED,
+            TusError::BadHeader(_) | TusError::Overflow | TusError::Body(_) => StatusCode::BAD_REQUEST,
+            TusError::ContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            TusError::Offset { .. } => StatusCode::CONFLICT,
+            TusError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            TusError::NotFound => StatusCode::NOT_FOUND,
+            TusError::Expired => StatusCode::GONE,
+            TusError::Locked => StatusCode::LOCKED,
+            TusError::Storage(_) | TusError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            TusError::Rejected(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut builder = tus_response(self.status_code());
+        if let TusError::Version = self {
+            builder.insert_header(("Tus-Version", TUS_VERSION));
+        }
+        if let TusError::Rejected(e) = self {
+            // Keep headers like WWW-Authenticate
+            for (name, value) in e.error_response().headers() {
+                builder.insert_header((name.clone(), value.clone()));
+            }
+        }
+        builder.json(serde_json::json!({ "error": self.to_string() }))
+    }
+}
+
+impl From<rusqlite::Error> for TusError {
+    fn from(e: rusqlite::Error) -> Self {
+        TusError::Db(e.to_string())
+    }
+}
+
+impl From<r2d2::Error> for TusError {
+    fn from(e: r2d2::Error) -> Self {
+        TusError::Db(e.to_string())
+    }
+}
+
+impl From<auth::AuthError> for TusError {
+    fn from(e: auth::AuthError) -> Self {
+        TusError::Rejected(e.into())
+    }
+}
+
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header(("Tus-Resumable", TUS_VERSION));
+    builder
+}
+
+fn check_version(req: &HttpRequest) -> Result<(), TusError> {
+    match req.headers().get("tus-resumable") {
+        Some(version) if version == TUS_VERSION => Ok(()),
+        _ => Err(TusError::Version),
+    }
+}
+
+fn header_u64(req: &HttpRequest, name: &'static str) -> Result<u64, TusError> {
+    req.headers().get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or(TusError::BadHeader(name))
+}
+
+fn has_offset_body(req: &HttpRequest) -> bool {
+    req.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case(OFFSET_OCTET_STREAM))
+}
+
+/// Decode an `Upload-Metadata` header: comma-separated pairs of a key and a
+/// base64 value, which may be left out. Returns `None` if it is malformed.
+pub fn parse_metadata(header: &str) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    for pair in header.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut parts = pair.split(' ').filter(|p| !p.is_empty());
+        let key = parts.next()?;
+        let value = match parts.next() {
+            Some(encoded) => {
+                let bytes = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+                String::from_utf8(bytes).ok()?
+            }
+            None => String::new(),
+        };
+        if parts.next().is_some() || metadata.insert(key.to_string(), value).is_some() {
+            return None;
+        }
+    }
+    Some(metadata)
+}
+
+/// `Upload-Expires` value for a database timestamp
+fn http_date(timestamp: &str) -> Option<String> {
+    let time = chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S").ok()?;
+    Some(time.and_utc().format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+fn progress_headers(builder: &mut HttpResponseBuilder, upload: &UploadRecord) {
+    builder.insert_header(("Upload-Offset", upload.received.to_string()));
+    if upload.completed_at.is_none() {
+        if let Some(expires) = http_date(&upload.expires_at) {
+            builder.insert_header(("Upload-Expires", expires));
+        }
+    }
+}
+
+/// Uploads being written by a request in this process. Another request
+/// appending at the same time would interleave its bytes, so it is refused.
+static WRITING: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+struct WriteLock(String);
+
+impl WriteLock {
+    fn acquire(id: &str) -> Result<Self, TusError> {
+        let mut writing = WRITING.lock().unwrap_or_else(|e| e.into_inner());
+        if !writing.insert(id.to_string()) {
+            return Err(TusError::Locked);
+        }
+        Ok(WriteLock(id.to_string()))
+    }
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        WRITING.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0);
+    }
+}
+
+fn expiry(data: &AppState) -> Duration {
+    Duration::from_secs(data.config.upload_expiry_secs)
+}
+
+/// Remove an upload and its partial data.
+async fn discard(data: &AppState, id: &str) -> Result<(), TusError> {
+    {
+        let conn = data.db_pool.get()?;
+        db_utils::delete_upload(&conn, id)?;
+    }
+    data.storage.delete(&storage::upload_key(id)).await?;
+    Ok(())
+}
+
+/// The upload with ID `id`. Expired ones are removed on sight.
+async fn load(data: &AppState, id: &str) -> Result<UploadRecord, TusError> {
+    if Uuid::parse_str(id).is_err() {
+        return Err(TusError::NotFound);
+    }
+    let upload = {
+        let conn = data.db_pool.get()?;
+        db_utils::get_upload(&conn, id)?.ok_or(TusError::NotFound)?
+    };
+    if upload.expired {
+        discard(data, id).await?;
+        return Err(TusError::Expired);
+    }
+    Ok(upload)
+}
+
+/// The upload with ID `id`, if the request may touch it: only the key that
+/// created an upload, or an admin, can see or change it. Anyone else gets
+/// 404 as if it didn't exist. Without auth there are no keys to tell apart.
+async fn load_own(data: &AppState, req: &HttpRequest, id: &str) -> Result<UploadRecord, TusError> {
+    let upload = load(data, id).await?;
+    if auth::require(req, Scope::Admin).is_err() && upload.uploader != auth::uploader(req) {
+        return Err(TusError::NotFound);
+    }
+    Ok(upload)
+}
+
+/// Append a request body to `key`, refusing anything past `remaining` bytes.
+///
+/// The payload is tied to the request's thread, so chunks are forwarded to
+/// the backend through a channel, as in `multipart_utils::write_temp_file`.
+async fn append_body(mut payload: web::Payload, storage: &dyn StorageBackend, key: &str, remaining: u64) -> Result<u64, TusError> {
+    let (mut tx, rx) = futures::channel::mpsc::channel::<std::io::Result<Bytes>>(8);
+    let forward = async move {
+        let mut remaining = remaining;
+        while let Some(chunk) = payload.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+                    return Err(TusError::Body(e.to_string()));
+                }
+            };
+            if chunk.len() as u64 > remaining {
+                let _ = tx.send(Ok(chunk.slice(..remaining as usize))).await;
+                let _ = tx.send(Err(std::io::Error::other("Body runs past Upload-Length"))).await;
+                return Err(TusError::Overflow);
+            }
+            remaining -= chunk.len() as u64;
+            if tx.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    };
+    let (forwarded, appended) = futures::join!(forward, storage.append(key, rx.boxed()));
+    forwarded?;
+    Ok(appended?)
+}
+
+/// Store the body of a PATCH (or creating POST) at the end of `upload` and
+/// record the progress, which counts even if the body broke off. Completes
+/// the upload once every byte is in.
+async fn receive(data: &AppState, upload: UploadRecord, payload: web::Payload) -> Result<UploadRecord, TusError> {
+    let key = storage::upload_key(&upload.id);
+    let remaining = (upload.length - upload.received) as u64;
+    let appended = append_body(payload, data.storage.as_ref(), &key, remaining).await;
+    let stored = data.storage.stat(&key).await?.map_or(0, |meta| meta.size);
+    let upload = {
+        let conn = data.db_pool.get()?;
+        db_utils::advance_upload(&conn, &upload.id, stored as i64, expiry(data))?.ok_or(TusError::NotFound)?
+    };
+    appended?;
+    if upload.received == upload.length {
+        complete(data, &upload).await?;
+        return load(data, &upload.id).await;
+    }
+    Ok(upload)
+}
+
+/// Run a fully received upload through the same checks as any other upload
+/// and store it as the file with the upload's ID. An upload that fails them
+/// is removed; one that can't be stored right now is kept, and a PATCH at its
+/// final offset tries again.
+async fn complete(data: &AppState, upload: &UploadRecord) -> Result<(), TusError> {
+    let key = storage::upload_key(&upload.id);
+    let metadata = upload.metadata.as_deref().and_then(parse_metadata).unwrap_or_default();
+    let filename = metadata.get("filename").or_else(|| metadata.get("name"))
+        .filter(|name| !name.is_empty())
+        .cloned()
+        .unwrap_or_else(|| "file".to_string());
+
+    // Hash, size-check and sniff the assembled file in one read
+    let mut inspector = StreamInspector::new(&data.config, &filename);
+    let mut stream = data.storage.get(&key).await?;
+    while let Some(chunk) = stream.next().await {
+        if let Err(e) = inspector.update(&chunk?) {
+            discard(data, &upload.id).await?;
+            return Err(TusError::Rejected(actix_web::error::ErrorPayloadTooLarge(e.to_string())));
+        }
+    }
+    let inspected = match inspector.finish() {
+        Ok(inspected) => inspected,
+        Err(e) => {
+            discard(data, &upload.id).await?;
+            return Err(TusError::Rejected(actix_web::error::ErrorPayloadTooLarge(e.to_string())));
+        }
+    };
+    let file_type = match validate_file_type(&inspected.head, &filename, &data.config.allowed_mime_types) {
+        Ok(file_type) => file_type,
+        Err(e) => {
+            discard(data, &upload.id).await?;
+            return Err(TusError::Rejected(e.into()));
+        }
+    };
+
+    let media_info = media_info::extract(&inspected.head, &file_type.mime_type);
+    let staged = ingest::Staged { temp_key: key, hash: inspected.hash, size: inspected.size, file_type, media_info };
+    let stored = ingest::store_staged(data, &staged, ingest::FileInfo {
+        uuid: upload.id.clone(),
+        original_filename: Some(filename),
+        uploader: upload.uploader.clone(),
+        tags: ingest::normalize_tags(metadata.get("tags").map_or("", String::as_str).split(',')),
+        ..Default::default()
+    }).await.map_err(|e| TusError::Rejected(e.into()))?;
+    log::debug!("Stored resumable upload {} (deduplicated: {})", stored.uuid, stored.deduplicated);
+    // Left behind if the content was already stored
+    let _ = data.storage.delete(&staged.temp_key).await;
+    let conn = data.db_pool.get()?;
+    db_utils::complete_upload(&conn, &upload.id)?;
+    Ok(())
+}
+
+/// Tell clients what the server supports.
+#[route("/uploads", method = "OPTIONS")]
+pub async fn tus_options(data: web::Data<AppState>) -> HttpResponse {
+    tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", data.config.max_size_ceiling().to_string()))
+        .finish()
+}
+
+/// Create an upload of `Upload-Length` bytes, optionally with its first data.
+#[post("/uploads")]
+pub async fn create_upload(
+    data: web::Data<AppState>,
+    payload: web::Payload,
+    req: HttpRequest,
+) -> Result<HttpResponse, TusError> {
+    auth::require(&req, Scope::Upload)?;
+    check_version(&req)?;
+    let length = header_u64(&req, "upload-length")?;
+    let limit = data.config.max_size_ceiling();
+    if length > limit {
+        return Err(TusError::TooLarge(limit));
+    }
+    let metadata = match req.headers().get("upload-metadata") {
+        Some(value) => {
+            let value = value.to_str().map_err(|_| TusError::BadHeader("Upload-Metadata"))?;
+            parse_metadata(value).ok_or(TusError::BadHeader("Upload-Metadata"))?;
+            Some(value.to_string())
+        }
+        None => None,
+    };
+
+    let id = Uuid::new_v4().to_string();
+    let location = {
+        let conn_info = req.connection_info();
+        format!("{}://{}/uploads/{}", conn_info.scheme(), conn_info.host(), id)
+    };
+    let uploader = auth::uploader(&req);
+    let upload = {
+        let conn = data.db_pool.get()?;
+        db_utils::insert_upload(&conn, &id, length as i64, metadata.as_deref(), uploader.as_deref(), expiry(&data))?
+    };
+
+    let with_body = has_offset_body(&req);
+    let upload = if with_body || length == 0 {
+        let _lock = WriteLock::acquire(&id)?;
+        receive(&data, upload, payload).await?
+    } else {
+        upload
+    };
+    let mut response = tus_response(StatusCode::CREATED);
+    response.insert_header((header::LOCATION, location));
+    progress_headers(&mut response, &upload);
+    Ok(response.finish())
+}
+
+/// How much of an upload the server has.
+#[route("/uploads/{upload_id}", method = "HEAD")]
+pub async fn upload_status(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, TusError> {
+    auth::require(&req, Scope::Upload)?;
+    check_version(&req)?;
+    let upload = load_own(&data, &req, &path.into_inner()).await?;
+    let mut response = tus_response(StatusCode::OK);
+    response.insert_header(("Upload-Length", upload.length.to_string()));
+    if let Some(metadata) = &upload.metadata {
+        response.insert_header(("Upload-Metadata", metadata.as_str()));
+    }
+    response.insert_header((header::CACHE_CONTROL, "no-store"));
+    progress_headers(&mut response, &upload);
+    Ok(response.finish())
+}
+
+/// Append data at `Upload-Offset`, which must be where the upload stands.
+#[patch("/uploads/{upload_id}")]
+pub async fn append_upload(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    payload: web::Payload,
+    req: HttpRequest,
+) -> Result<HttpResponse, TusError> {
+    auth::require(&req, Scope::Upload)?;
+    check_version(&req)?;
+    if !has_offset_body(&req) {
+        return Err(TusError::ContentType);
+    }
+    let given = header_u64(&req, "upload-offset")?;
+    let id = path.into_inner();
+    let _lock = WriteLock::acquire(&id)?;
+    let upload = load_own(&data, &req, &id).await?;
+    let received = upload.received as u64;
+    if given != received {
+        return Err(TusError::Offset { given, received });
+    }
+    // A retry after the response to the last PATCH was lost
+    let upload = if upload.completed_at.is_some() {
+        upload
+    } else {
+        receive(&data, upload, payload).await?
+    };
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    progress_headers(&mut response, &upload);
+    Ok(response.finish())
+}
+
+/// Abandon an upload and delete its data. The file of a completed upload is
+/// not affected; delete it with `DELETE /files/{id}`.
+#[delete("/uploads/{upload_id}")]
+pub async fn terminate_upload(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, TusError> {
+    auth::require(&req, Scope::Upload)?;
+    check_version(&req)?;
+    let id = path.into_inner();
+    let _lock = WriteLock::acquire(&id)?;
+    load_own(&data, &req, &id).await?;
+    discard(&data, &id).await?;
+    Ok(tus_response(StatusCode::NO_CONTENT).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata = parse_metadata("filename d29ybGQucG5n, tags YSxi,is_confidential").unwrap();
+        assert_eq!(metadata["filename"], "world.png");
+        assert_eq!(metadata["tags"], "a,b");
+        assert_eq!(metadata["is_confidential"], "");
+        assert_eq!(parse_metadata("").unwrap().len(), 0);
+        assert!(parse_metadata("filename not-base64!").is_none());
+        assert!(parse_metadata("a YQ==,a Yg==").is_none());
+        assert!(parse_metadata("a YQ== Yg==").is_none());
+        assert_eq!(http_date("2024-05-01 12:00:00").unwrap(), "Wed, 01 May 2024 12:00:00 GMT");
+    }
+}
@@ -1,30 +1,70 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use tokio::sync::Semaphore;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 use log::{info, error, debug};
-use sha2::{Sha256, Digest};
-use std::fs::File;
-use std::io::Write;
+use futures_util::StreamExt;
 use log::warn;
 
 use crate::db_utils;
+use crate::fetch;
+use crate::file_utils;
+use crate::{ingest, media_info};
+use crate::storage;
 use crate::AppState;
 
+/// A download that got an HTTP error status back
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to download file: {0}")]
+pub struct BadStatus(pub reqwest::StatusCode);
+
+/// Whether a failed download might succeed if tried again. Network trouble,
+/// server errors and rate limiting are worth retrying; files the server
+/// refuses or that we reject (bad URL, too large, disallowed type) are not.
+pub fn is_transient(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    if let Some(BadStatus(status)) = e.downcast_ref::<BadStatus>() {
+        return status.is_server_error()
+            || *status == reqwest::StatusCode::REQUEST_TIMEOUT
+            || *status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+    }
+    if e.is::<file_utils::PayloadTooLarge>() || e.is::<file_utils::FileTypeError>() {
+        return false;
+    }
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return file_utils::as_payload_too_large(e).is_none();
+    }
+    if fetch::as_blocked_url(e).is_some() {
+        return false;
+    }
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        return !e.is_builder();
+    }
+    true
+}
+
 #[derive(Debug, Clone)]
 pub struct DownloadWorker {
     state: Arc<AppState>,
     max_concurrent_downloads: usize,
     running: Arc<AtomicBool>,
+    // Shared so connections are pooled; enforces `Config::fetch`
+    client: reqwest::Client,
+    // Tokens for the jobs currently downloading, so they can be cancelled
+    in_flight: Arc<Mutex<HashMap<String, CancellationToken>>>,
 }
 
 impl DownloadWorker {
     pub fn new(state: Arc<AppState>, max_concurrent_downloads: usize) -> Self {
+        let client = fetch::client(&state.config.fetch).expect("Failed to build the download HTTP client");
         Self {
             state,
+            client,
             max_concurrent_downloads,
             running: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -39,6 +79,21 @@ impl DownloadWorker {
 
         tokio::spawn(async move {
             info!("Starting download worker with {} max concurrent downloads", worker.max_concurrent_downloads);
+            // Nothing can be running yet, so whatever a previous process left
+            // Running or half-written was abandoned
+            if let Err(e) = worker.recover_stale_jobs(true).await {
+                error!("Failed to recover interrupted jobs: {}", e);
+            }
+            if let Err(e) = worker.sweep_temp_files().await {
+                error!("Failed to remove leftover temporary files: {}", e);
+            }
+            if let Err(e) = worker.purge_expired_uploads().await {
+                error!("Failed to remove expired uploads: {}", e);
+            }
+            if let Err(e) = worker.purge_expired_files().await {
+                error!("Failed to remove expired files: {}", e);
+            }
+            worker.spawn_reaper();
             let semaphore = Arc::new(Semaphore::new(worker.max_concurrent_downloads));
             
             while running.load(Ordering::SeqCst) {
@@ -59,24 +114,173 @@ impl DownloadWorker {
         });
     }
 
+    /// Periodically requeue jobs whose lease ran out, e.g. because the task
+    /// running them hung, and remove expired files and resumable uploads,
+    /// until the worker stops.
+    fn spawn_reaper(&self) {
+        let worker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(worker.lease());
+            interval.tick().await;
+            while worker.running.load(Ordering::SeqCst) {
+                interval.tick().await;
+                if let Err(e) = worker.recover_stale_jobs(false).await {
+                    error!("Failed to recover stale jobs: {}", e);
+                }
+                if let Err(e) = worker.purge_expired_uploads().await {
+                    error!("Failed to remove expired uploads: {}", e);
+                }
+                if let Err(e) = worker.purge_expired_files().await {
+                    error!("Failed to remove expired files: {}", e);
+                }
+            }
+        });
+    }
+
     pub fn stop(&self) {
         self.running.store(false, Ordering::SeqCst);
     }
 
+    /// Abort the download for `job_id` if it is in progress. The job's status
+    /// is the caller's business; see `db_utils::cancel_job`.
+    pub fn cancel(&self, job_id: &str) -> bool {
+        let in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        match in_flight.get(job_id) {
+            Some(token) => {
+                info!("Cancelling download for job {}", job_id);
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lease(&self) -> Duration {
+        Duration::from_secs(self.state.config.job_lease_secs)
+    }
+
+    /// Requeue jobs abandoned by a worker that stopped heartbeating (every
+    /// running job if `all`), and delete their partial downloads.
+    async fn recover_stale_jobs(&self, all: bool) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let job_ids = {
+            let conn = self.state.db_pool.get()?;
+            db_utils::recover_stale_jobs(&conn, all)?
+        };
+        for job_id in &job_ids {
+            warn!("Requeued job {} after its worker stopped", job_id);
+            // Make sure a hung download of ours doesn't keep writing
+            self.cancel(job_id);
+            self.state.storage.delete(&storage::temp_key(job_id)).await?;
+        }
+        Ok(job_ids.len())
+    }
+
+    /// Remove everything in the temporary area. Only safe before any upload
+    /// or download has started.
+    async fn sweep_temp_files(&self) -> std::io::Result<usize> {
+        let leftovers: Vec<storage::ObjectMeta> = self.state.storage.list("tmp/").await?
+            .collect::<Vec<_>>().await
+            .into_iter()
+            .collect::<std::io::Result<_>>()?;
+        for meta in &leftovers {
+            debug!("Removing leftover temporary object: {}", meta.key);
+            self.state.storage.delete(&meta.key).await?;
+        }
+        if !leftovers.is_empty() {
+            info!("Removed {} leftover temporary objects", leftovers.len());
+        }
+        Ok(leftovers.len())
+    }
+
+    /// Remove resumable uploads that received nothing for `upload_expiry_secs`,
+    /// along with their partial data.
+    async fn purge_expired_uploads(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let upload_ids = {
+            let conn = self.state.db_pool.get()?;
+            db_utils::delete_expired_uploads(&conn)?
+        };
+        for upload_id in &upload_ids {
+            debug!("Removing expired upload: {}", upload_id);
+            self.state.storage.delete(&storage::upload_key(upload_id)).await?;
+        }
+        if !upload_ids.is_empty() {
+            info!("Removed {} expired uploads", upload_ids.len());
+        }
+        Ok(upload_ids.len())
+    }
+
+    /// Delete the files whose `expires_at` has passed.
+    async fn purge_expired_files(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let file_ids = {
+            let conn = self.state.db_pool.get()?;
+            db_utils::expired_files(&conn)?
+        };
+        for file_id in &file_ids {
+            debug!("Removing expired file: {}", file_id);
+            ingest::delete_file(&self.state, file_id).await?;
+        }
+        if !file_ids.is_empty() {
+            info!("Removed {} expired files", file_ids.len());
+        }
+        Ok(file_ids.len())
+    }
+
+    /// Keep the lease on a running job alive. Returns once the job is no
+    /// longer ours, e.g. because it was requeued after a stall.
+    async fn heartbeat(&self, job_id: &str) {
+        let lease = self.lease();
+        let mut interval = tokio::time::interval(lease / 3);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let conn = match self.state.db_pool.get() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to renew lease for job {}: {}", job_id, e);
+                    continue;
+                }
+            };
+            match db_utils::renew_job_lease(&conn, job_id, lease) {
+                Ok(true) => debug!("Renewed lease for job {}", job_id),
+                Ok(false) => return,
+                Err(e) => warn!("Failed to renew lease for job {}: {}", job_id, e),
+            }
+        }
+    }
+
     async fn process_next_job(&self, semaphore: Arc<Semaphore>) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        // Wait for a free slot first, so a job is only marked running (and
+        // leased) once it can actually start
+        let permit = semaphore.acquire_owned().await?;
         let conn = self.state.db_pool.get()?;
         
-        // Find a job that's not started and mark it as running
-        match db_utils::get_and_start_job(&conn)? {
+        // Find a job that's due and mark it as running
+        match db_utils::get_and_start_job(&conn, self.lease())? {
             Some(job) => {
                 let worker = self.clone();
-                let permit = semaphore.acquire_owned().await?;
                 
                 tokio::spawn(async move {
                     debug!("Processing job: {}", job.id);
+                    let token = CancellationToken::new();
+                    worker.in_flight.lock().unwrap_or_else(|e| e.into_inner())
+                        .insert(job.id.clone(), token.clone());
                     
-                    // Download the file
-                    let result = worker.download_file(&job.id, &job.download_url).await;
+                    // Download the file, unless the job is cancelled or taken
+                    // away from us first
+                    let result = tokio::select! {
+                        result = worker.download_file(&job.id, &job.download_url) => Some(result),
+                        _ = token.cancelled() => None,
+                        _ = worker.heartbeat(&job.id) => None,
+                    };
+                    worker.in_flight.lock().unwrap_or_else(|e| e.into_inner()).remove(&job.id);
+                    let result = match result {
+                        Some(result) => result,
+                        None => {
+                            info!("Job {} was cancelled or is no longer running", job.id);
+                            let _ = worker.state.storage.delete(&storage::temp_key(&job.id)).await;
+                            return;
+                        }
+                    };
                     
                     // Update job status
                     let conn = match worker.state.db_pool.get() {
@@ -89,14 +293,25 @@ impl DownloadWorker {
                     
                     match result {
                         Ok(file_id) => {
-                            if let Err(e) = db_utils::complete_job(&conn, &job.id, file_id) {
-                                error!("Failed to mark job as completed: {}", e);
-                            } else {
-                                info!("Successfully processed job: {}", job.id);
+                            match db_utils::complete_job(&conn, &job.id, file_id) {
+                                Ok(true) => info!("Successfully processed job: {}", job.id),
+                                Ok(false) => warn!("Job {} finished after it was cancelled", job.id),
+                                Err(e) => error!("Failed to mark job as completed: {}", e),
+                            }
+                        }
+                        Err(e) if is_transient(e.as_ref()) && job.attempts < job.max_attempts => {
+                            let policy = &worker.state.config.retry;
+                            let delay = policy.delay_after(job.attempts as u32, rand::random::<f64>());
+                            warn!("Job {} attempt {}/{} failed, retrying in {:.1}s: {}",
+                                job.id, job.attempts, job.max_attempts, delay.as_secs_f64(), e);
+                            match db_utils::retry_job(&conn, &job.id, &e.to_string(), delay) {
+                                Ok(true) => {}
+                                Ok(false) => warn!("Job {} failed after it was cancelled", job.id),
+                                Err(e) => error!("Failed to schedule retry: {}", e),
                             }
                         }
                         Err(e) => {
-                            error!("Job {} failed: {}", job.id, e);
+                            error!("Job {} failed after {} attempt(s): {}", job.id, job.attempts, e);
                             if let Err(e) = db_utils::fail_job(&conn, &job.id, &e.to_string()) {
                                 error!("Failed to mark job as failed: {}", e);
                             }
@@ -115,102 +330,142 @@ impl DownloadWorker {
     async fn download_file(&self, job_id: &str, url: &str) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
         info!("Starting download for job {} from URL: {}", job_id, url);
         
-        // Create a temporary file path
-        let temp_path = self.state.media_path.join(format!("{}.tmp", job_id));
-        let final_path = self.state.media_path.join(job_id);
-        debug!("Temporary file path: {:?}", temp_path);
-        debug!("Final file path: {:?}", final_path);
+        // Create a temporary storage key
+        let temp_key = storage::temp_key(job_id);
+        debug!("Temporary storage key: {}", temp_key);
         
-        // Download the file
+        // Download the file. The URL was checked when the job was submitted,
+        // but the rules may have changed since
+        let parsed = fetch::parse_url(url, &self.state.config.fetch)?;
+        let read_timeout = Duration::from_secs(self.state.config.fetch.read_timeout_secs);
         info!("Initiating HTTP GET request to: {}", url);
-        let response = reqwest::get(url).await?;
+        let response = tokio::time::timeout(read_timeout, self.client.get(parsed).send())
+            .await
+            .map_err(|_| timed_out("response headers"))??;
         let status = response.status();
         info!("Received response with status: {}", status);
         
         if !status.is_success() {
-            let error_msg = format!("Failed to download file: {}", status);
-            error!("{}", error_msg);
-            return Err(error_msg.into());
+            error!("Failed to download file: {}", status);
+            return Err(Box::new(BadStatus(status)));
         }
         
         // Get content type before consuming the response
         let content_type = response
             .headers()
             .get(reqwest::header::CONTENT_TYPE)
-            .and_then(|ct| {
+            .map(|ct| {
                 let ct_str = ct.to_str().unwrap_or("<invalid-header>");
                 info!("Content-Type: {}", ct_str);
-                Some(ct_str)
+                ct_str
             })
             .unwrap_or("application/octet-stream")
             .to_string();
             
-        info!("Downloading content...");
-        let content = response.bytes().await?;
-        info!("Downloaded {} bytes", content.len());
+        let filename = remote_filename(url, response.headers(), &content_type);
+        debug!("Remote filename: {}", filename);
         
-        // Calculate hash
-        info!("Calculating SHA-256 hash of downloaded content...");
-        let hash = format!("{:x}", Sha256::digest(&content));
-        debug!("File hash: {}", hash);
-        
-        // Check for duplicates
-        info!("Checking for existing files with the same hash...");
-        let conn = self.state.db_pool.get()?;
-        if let Some(existing_path) = db_utils::get_filepath_by_hash(&conn, &hash)? {
-            // File already exists, return the existing file ID
-            info!("Found existing file with same hash at: {}", existing_path);
-            let file_id = db_utils::get_file_id_by_path(&conn, &existing_path)?;
-            info!("Returning existing file ID: {}", file_id);
-            return Ok(file_id);
-        } else {
-            info!("No existing file found with hash: {}", hash);
+        // Refuse oversized bodies up front when the server tells us their size
+        if let Some(length) = response.content_length() {
+            let ceiling = self.state.config.max_size_ceiling();
+            if length > ceiling {
+                return Err(Box::new(file_utils::PayloadTooLarge {
+                    limit: ceiling,
+                    mime_type: content_type,
+                }));
+            }
         }
         
-        // Write to temporary file first
-        info!("Writing {} bytes to temporary file: {:?}", content.len(), temp_path);
-        let mut file = File::create(&temp_path)?;
-        file.write_all(&content)?;
-        info!("Successfully wrote to temporary file");
-        
-        // Determine file extension from content type
-        let extension = match content_type.split('/').nth(1) {
-            Some(ext) => {
-                let ext = ext.split(';').next().unwrap_or("");
-                info!("Determined file extension from content type: {}", ext);
-                format!(".{}", ext)
-            },
-            None => {
-                warn!("Could not determine file extension from content type: {}", content_type);
-                String::new()
-            },
+        // Stream the body to a temporary object, hashing and size-checking
+        // each chunk on the way so the file is never held in memory
+        info!("Streaming content to temporary object: {}", temp_key);
+        let inspector = Arc::new(Mutex::new(file_utils::StreamInspector::new(&self.state.config, &filename)));
+        let body = futures_util::stream::unfold(response, move |mut response| async move {
+            match tokio::time::timeout(read_timeout, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => Some((Ok(chunk), response)),
+                Ok(Ok(None)) => None,
+                Ok(Err(e)) => Some((Err(std::io::Error::other(e)), response)),
+                Err(_) => Some((Err(timed_out("response body")), response)),
+            }
+        }).boxed();
+        let written = self.state.storage
+            .put(&temp_key, file_utils::inspect_stream(body, inspector.clone()))
+            .await;
+        let inspected = written.and_then(|_| file_utils::finish_inspection(inspector));
+        let inspected = match inspected {
+            Ok(inspected) => inspected,
+            Err(e) => {
+                let _ = self.state.storage.delete(&temp_key).await;
+                return Err(e.into());
+            }
         };
+        info!("Downloaded {} bytes", inspected.size);
+        let hash = inspected.hash;
+        debug!("File hash: {}", hash);
         
-        // Rename to final path with extension
-        let final_path = final_path.with_extension(extension);
-        info!("Moving temporary file to final location: {:?}", final_path);
-        std::fs::rename(&temp_path, &final_path)?;
-        info!("File successfully moved to final location");
+        // Judge the file by its content, exactly like an upload
+        let file_type = match file_utils::validate_file_type(&inspected.head, &filename, &self.state.config.allowed_mime_types) {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                warn!("Rejecting download for job {}: {}", job_id, e);
+                let _ = self.state.storage.delete(&temp_key).await;
+                return Err(e.into());
+            }
+        };
+        info!("Detected file type {} (.{})", file_type.mime_type, file_type.extension);
         
-        // Insert file record
-        let download_url = format!("/files/{}", job_id);
-        info!("Inserting file record into database...");
-        let file_id = db_utils::insert_file(&conn, final_path.to_str().unwrap(), &download_url, &hash)?;
-        info!("Successfully inserted file record with ID: {}", file_id);
+        // Store it as a file of its own, sharing the blob of identical content
+        let media_info = media_info::extract(&inspected.head, &file_type.mime_type);
+        let staged = ingest::Staged { temp_key, hash, size: inspected.size, file_type, media_info };
+        let stored = ingest::store_file(&self.state, &staged, ingest::FileInfo {
+            uuid: job_id.to_string(),
+            original_filename: Some(filename),
+            source_url: Some(url.to_string()),
+            ..Default::default()
+        }).await?;
+        info!("Successfully inserted file record with ID: {} (deduplicated: {})", stored.id, stored.deduplicated);
         
+        let file_id = stored.id;
         Ok(file_id)
     }
 }
 
+fn timed_out(waiting_for: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, format!("Timed out waiting for {}", waiting_for))
+}
+
+/// Name for a remote file: the `Content-Disposition` filename if the server
+/// sent one, else the last URL path segment. When neither has an extension,
+/// one is derived from the `Content-Type` so text formats that can't be
+/// sniffed still get the same extension fallback as uploads.
+fn remote_filename(url: &str, headers: &reqwest::header::HeaderMap, content_type: &str) -> String {
+    let disposition = headers
+        .get(reqwest::header::CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.split(';')
+                .filter_map(|part| part.trim().strip_prefix("filename="))
+                .map(|name| name.trim_matches('"').to_string())
+                .next()
+        });
+    let from_url = || {
+        reqwest::Url::parse(url).ok()
+            .and_then(|u| u.path_segments().and_then(|mut s| s.next_back()).map(str::to_string))
+    };
+    let name = disposition.or_else(from_url)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "download".to_string());
+    file_utils::with_type_extension(&name, content_type)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile::tempdir;
+    use crate::MemoryStorage;
     use std::sync::atomic::Ordering;
     
     #[tokio::test]
     async fn test_worker_stop() {
-        let temp_dir = tempdir().unwrap();
         let db_file = tempfile::NamedTempFile::new().unwrap();
         let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
         let db_pool = r2d2::Pool::new(manager).unwrap();
@@ -222,8 +477,9 @@ mod tests {
         }
         
         let state = Arc::new(AppState {
-            media_path: temp_dir.path().to_path_buf(),
+            storage: Arc::new(MemoryStorage::new()),
             db_pool: db_pool.clone(),
+            config: crate::Config::default(),
             worker: None,
         });
         
@@ -245,7 +501,6 @@ mod tests {
     }
     #[tokio::test]
     async fn test_worker_start_twice() {
-        let temp_dir = tempdir().unwrap();
         let db_file = tempfile::NamedTempFile::new().unwrap();
         let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
         let db_pool = r2d2::Pool::new(manager).unwrap();
@@ -254,8 +509,9 @@ mod tests {
             db_utils::init_db(&conn).unwrap();
         }
         let state = Arc::new(AppState {
-            media_path: temp_dir.path().to_path_buf(),
+            storage: Arc::new(MemoryStorage::new()),
             db_pool: db_pool.clone(),
+            config: crate::Config::default(),
             worker: None,
         });
         let worker = DownloadWorker::new(Arc::clone(&state), 2);
@@ -269,7 +525,6 @@ mod tests {
 
     #[tokio::test]
     async fn test_process_next_job_none() {
-        let temp_dir = tempdir().unwrap();
         let db_file = tempfile::NamedTempFile::new().unwrap();
         let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
         let db_pool = r2d2::Pool::new(manager).unwrap();
@@ -278,8 +533,9 @@ mod tests {
             db_utils::init_db(&conn).unwrap();
         }
         let state = Arc::new(AppState {
-            media_path: temp_dir.path().to_path_buf(),
+            storage: Arc::new(MemoryStorage::new()),
             db_pool: db_pool.clone(),
+            config: crate::Config::default(),
             worker: None,
         });
         let worker = DownloadWorker::new(Arc::clone(&state), 1);
@@ -289,4 +545,224 @@ mod tests {
         assert!(result.is_ok());
         assert!(!result.unwrap());
     }
+
+    /// Serve `body` once over plain HTTP on a random local port and return its URL.
+    async fn serve_once(body: Vec<u8>, content_type: &'static str, path: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type, body.len()
+            );
+            let _ = socket.write_all(head.as_bytes()).await;
+            let _ = socket.write_all(&body).await;
+        });
+        format!("http://{}{}", addr, path)
+    }
+
+    fn test_state(db_file: &tempfile::NamedTempFile, config: crate::Config) -> Arc<AppState> {
+        let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+        let db_pool = r2d2::Pool::new(manager).unwrap();
+        db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+        Arc::new(AppState {
+            storage: Arc::new(MemoryStorage::new()),
+            db_pool,
+            config,
+            worker: None,
+        })
+    }
+
+    // `serve_once` listens on loopback, which downloads may not reach by default
+    fn loopback_config() -> crate::Config {
+        crate::Config {
+            fetch: crate::FetchConfig { allow_private_ips: true, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_file_streams_and_hashes() {
+        let body = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/.data/example.png")).unwrap();
+        let url = serve_once(body.clone(), "image/png", "/media/example.png").await;
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let state = test_state(&db_file, loopback_config());
+        let worker = DownloadWorker::new(Arc::clone(&state), 1);
+
+        let file_id = worker.download_file("123e4567-e89b-12d3-a456-426614174000", &url).await.unwrap();
+
+        let conn = state.db_pool.get().unwrap();
+        let file = db_utils::get_file_by_id(&conn, file_id).unwrap();
+        assert_eq!(file.hash, format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(&body)));
+        assert_eq!(file.size, Some(body.len() as i64));
+        assert_eq!(file.original_filename.as_deref(), Some("example.png"));
+        let stored = state.storage.stat(&file.filepath).await.unwrap().expect("Stored object missing");
+        assert_eq!(stored.size, body.len() as u64);
+        assert!(state.storage.stat(&storage::temp_key(&file.uuid)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_download_file_over_limit_fails() {
+        let body = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/.data/example.png")).unwrap();
+        let url = serve_once(body, "image/png", "/media/example.png").await;
+        let config = crate::Config {
+            max_file_size: 1024,
+            max_file_size_by_type: Default::default(),
+            ..loopback_config()
+        };
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let state = test_state(&db_file, config);
+        let worker = DownloadWorker::new(Arc::clone(&state), 1);
+
+        let err = worker.download_file("123e4567-e89b-12d3-a456-426614174000", &url).await.unwrap_err();
+        assert!(err.to_string().contains("1024 byte limit"), "Unexpected error: {}", err);
+        let leftovers: Vec<_> = state.storage.list("").await.unwrap().collect().await;
+        assert!(leftovers.is_empty(), "Nothing should be left in storage");
+    }
+
+    #[tokio::test]
+    async fn test_download_file_refuses_private_addresses() {
+        let body = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/.data/example.png")).unwrap();
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let state = test_state(&db_file, crate::Config::default());
+        let worker = DownloadWorker::new(Arc::clone(&state), 1);
+
+        // An IP literal is refused before connecting, a name once it resolves
+        let url = serve_once(body, "image/png", "/media/example.png").await;
+        let by_name = url.replace("127.0.0.1", "localhost");
+        for url in [url, by_name] {
+            let err = worker.download_file("123e4567-e89b-12d3-a456-426614174000", &url).await.unwrap_err();
+            assert!(matches!(fetch::as_blocked_url(err.as_ref()), Some(fetch::BlockedUrl::Address(_))), "Unexpected error: {}", err);
+            assert!(!is_transient(err.as_ref()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_file_rejects_disguised_content() {
+        let body = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/.data/disguised.png")).unwrap();
+        let url = serve_once(body, "image/png", "/media/disguised.png").await;
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let state = test_state(&db_file, loopback_config());
+        let worker = DownloadWorker::new(Arc::clone(&state), 1);
+
+        let err = worker.download_file("123e4567-e89b-12d3-a456-426614174000", &url).await.unwrap_err();
+        assert!(err.to_string().starts_with("File type not allowed"), "Unexpected error: {}", err);
+        let leftovers: Vec<_> = state.storage.list("").await.unwrap().collect().await;
+        assert!(leftovers.is_empty(), "Rejected download should be removed");
+    }
+
+    #[tokio::test]
+    async fn test_recover_stale_jobs() {
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let state = test_state(&db_file, crate::Config::default());
+        let worker = DownloadWorker::new(Arc::clone(&state), 1);
+        let conn = state.db_pool.get().unwrap();
+        let lease = Duration::from_secs(60);
+        for (id, max_attempts) in [("live-job", 3), ("last-try-job", 1)] {
+            db_utils::insert_job(&conn, id, &db_utils::JobStatus::NotStarted, None, "http://example.com/a.png", max_attempts).unwrap();
+            db_utils::get_and_start_job(&conn, lease).unwrap().unwrap();
+            state.storage.put(&storage::temp_key(id), storage::once("partial".into())).await.unwrap();
+        }
+
+        // Leases still valid: only a startup pass touches them
+        assert_eq!(worker.recover_stale_jobs(false).await.unwrap(), 0);
+        conn.execute("UPDATE Job SET lease_expires_at = datetime('now', '-1 seconds') WHERE id = 'live-job'", []).unwrap();
+        assert_eq!(worker.recover_stale_jobs(false).await.unwrap(), 1);
+        let job = db_utils::get_job_by_id(&conn, "live-job").unwrap().unwrap();
+        assert_eq!(job.status, db_utils::JobStatus::Retrying);
+        assert!(db_utils::get_job_attempts(&conn, "live-job").unwrap()[0].error.is_some());
+        assert!(state.storage.stat(&storage::temp_key("live-job")).await.unwrap().is_none());
+
+        // At startup every running job is abandoned; one out of attempts fails
+        assert_eq!(worker.recover_stale_jobs(true).await.unwrap(), 1);
+        let job = db_utils::get_job_by_id(&conn, "last-try-job").unwrap().unwrap();
+        assert_eq!(job.status, db_utils::JobStatus::Failed);
+
+        state.storage.put("tmp/orphan-upload.tmp", storage::once("x".into())).await.unwrap();
+        assert_eq!(worker.sweep_temp_files().await.unwrap(), 1);
+        let leftovers: Vec<_> = state.storage.list("").await.unwrap().collect().await;
+        assert!(leftovers.is_empty());
+
+        // Resumable uploads survive the sweep and go once they expire
+        let expires = Duration::from_secs(3600);
+        for id in ["live-upload", "stale-upload"] {
+            db_utils::insert_upload(&conn, id, 10, None, None, expires).unwrap();
+            state.storage.put(&storage::upload_key(id), storage::once("part".into())).await.unwrap();
+        }
+        assert_eq!(worker.sweep_temp_files().await.unwrap(), 0);
+        conn.execute("UPDATE Upload SET expires_at = datetime('now', '-1 seconds') WHERE id = 'stale-upload'", []).unwrap();
+        assert_eq!(worker.purge_expired_uploads().await.unwrap(), 1);
+        assert!(db_utils::get_upload(&conn, "stale-upload").unwrap().is_none());
+        assert!(state.storage.stat(&storage::upload_key("stale-upload")).await.unwrap().is_none());
+        assert!(state.storage.stat(&storage::upload_key("live-upload")).await.unwrap().is_some());
+
+        // The requeued job is picked up again with a fresh lease
+        let job = db_utils::get_and_start_job(&conn, lease).unwrap().unwrap();
+        assert_eq!((job.id.as_str(), job.attempts), ("live-job", 2));
+        assert!(db_utils::renew_job_lease(&conn, "live-job", lease).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired_files() {
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let state = test_state(&db_file, crate::Config::default());
+        let worker = DownloadWorker::new(Arc::clone(&state), 1);
+        let conn = state.db_pool.get().unwrap();
+        state.storage.put("ab/blob.png", storage::once("png".into())).await.unwrap();
+        let blob_id = db_utils::insert_blob(&conn, "hash", "ab/blob.png", 3).unwrap();
+        for (uuid, expires_at) in [("kept", None), ("later", Some("2999-01-01 00:00:00")), ("expired", Some("2000-01-01 00:00:00"))] {
+            db_utils::insert_file(&conn, &db_utils::NewFile {
+                uuid: uuid.into(),
+                blob_id,
+                expires_at: expires_at.map(str::to_string),
+                ..Default::default()
+            }).unwrap();
+        }
+        assert!(db_utils::get_file_by_uuid(&conn, "expired").unwrap().is_none());
+
+        assert_eq!(worker.purge_expired_files().await.unwrap(), 1);
+        assert_eq!(worker.purge_expired_files().await.unwrap(), 0);
+        assert!(db_utils::get_file_by_uuid(&conn, "later").unwrap().is_some());
+        assert!(state.storage.stat("ab/blob.png").await.unwrap().is_some());
+
+        // The blob goes with the last file using it
+        conn.execute("UPDATE File SET expires_at = datetime('now', '-1 seconds')", []).unwrap();
+        assert_eq!(worker.purge_expired_files().await.unwrap(), 2);
+        assert!(state.storage.stat("ab/blob.png").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let bad_status = |code: u16| -> Box<dyn std::error::Error + Send + Sync> {
+            Box::new(BadStatus(reqwest::StatusCode::from_u16(code).unwrap()))
+        };
+        assert!(is_transient(bad_status(503).as_ref()));
+        assert!(is_transient(bad_status(429).as_ref()));
+        assert!(!is_transient(bad_status(404).as_ref()));
+
+        let too_large = std::io::Error::other(file_utils::PayloadTooLarge { limit: 1, mime_type: "image/png".into() });
+        assert!(!is_transient(&too_large));
+        assert!(is_transient(&std::io::Error::from(std::io::ErrorKind::ConnectionReset)));
+        assert!(!is_transient(&file_utils::FileTypeError::Unknown));
+    }
+
+    #[test]
+    fn test_remote_filename() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(remote_filename("http://example.com/a/feed.rss", &headers, "text/html"), "feed.rss");
+        assert_eq!(remote_filename("http://example.com/api/items", &headers, "application/json; charset=utf-8"), "items.json");
+        headers.insert(reqwest::header::CONTENT_DISPOSITION, "attachment; filename=\"episode.mp3\"".parse().unwrap());
+        assert_eq!(remote_filename("http://example.com/dl?id=1", &headers, "audio/mpeg"), "episode.mp3");
+    }
 }
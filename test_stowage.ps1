@@ -55,7 +55,7 @@ foreach ($file in $files) {
         if ($status -eq '201') {
             Write-Host "  Success: $file uploaded."
             $json = $body | ConvertFrom-Json
-            $file_id = $json.file_id
+            $file_id = $json[0].file_id
             write-host "  File ID: $file_id"
             $download_url = "http://localhost:8080/files/$file_id"
             $tempDownload = [System.IO.Path]::GetTempFileName()
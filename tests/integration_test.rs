@@ -13,11 +13,7 @@ async fn test_db_contents_after_upload() {
     }
     let app = test::init_service(
         App::new()
-            .app_data(actix_web::web::Data::new(stowage::AppState {
-                media_path: media_path.path().to_path_buf(),
-                db_pool: db_pool.clone(),
-                worker: None
-            }))
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
             .configure(stowage::routes),
     )
     .await;
@@ -44,7 +40,7 @@ async fn test_db_contents_after_upload() {
     assert_eq!(resp.status(), 201, "File should upload");
     let body = test::read_body(resp).await;
     let resp_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
-    let file_id = resp_json["file_id"].as_str().unwrap();
+    let file_id = resp_json[0]["file_id"].as_str().unwrap();
     let download_url = format!("/files/{}", file_id);
 
     // Check DB contents
@@ -58,7 +54,15 @@ async fn test_db_contents_after_upload() {
     assert_eq!(db_url, download_url);
     assert_eq!(db_hash, expected_hash);
     // The file should exist on disk
-    assert!(std::path::Path::new(&db_filepath).exists());
+    assert!(media_path.path().join(&db_filepath).exists());
+
+    // Metadata is recorded alongside the row
+    let file = stowage::db_utils::get_file_by_uuid(&conn, file_id).unwrap().expect("File row missing");
+    assert_eq!(file.original_filename.as_deref(), Some("example.json"));
+    assert_eq!(file.mime_type.as_deref(), Some("application/json"));
+    assert_eq!(file.extension.as_deref(), Some("json"));
+    assert_eq!(file.size, Some(file_bytes.len() as i64));
+    assert!(file.created_at.is_some());
 }
 use actix_web::{test, App, http::StatusCode}; 
 use std::fs; 
@@ -90,11 +94,7 @@ async fn upload_and_download(file_name: &str, should_succeed: bool) {
     }
     let app = test::init_service(
         App::new()
-            .app_data(actix_web::web::Data::new(stowage::AppState {
-                media_path: media_path.path().to_path_buf(),
-                db_pool: db_pool.clone(),
-                worker: None
-            }))
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
             .configure(stowage::routes),
     )
     .await;
@@ -118,7 +118,7 @@ async fn upload_and_download(file_name: &str, should_succeed: bool) {
         assert_eq!(resp.status(), 201, "File {} should upload", file_name); 
         let body = test::read_body(resp).await; 
         let resp_json: serde_json::Value = serde_json::from_slice(&body).unwrap(); 
-        let file_id = resp_json["file_id"].as_str().unwrap(); 
+        let file_id = resp_json[0]["file_id"].as_str().unwrap(); 
         let download_uri = format!("/files/{}", file_id); 
         let req = test::TestRequest::get().uri(&download_uri).to_request(); 
         let resp = test::call_service(&app, req).await; 
@@ -128,6 +128,21 @@ async fn upload_and_download(file_name: &str, should_succeed: bool) {
     }
 }
 
+fn test_app_state(
+    media_path: &std::path::Path,
+    db_pool: &r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>,
+) -> stowage::AppState {
+    stowage::AppState {
+        storage: std::sync::Arc::new(stowage::LocalStorage::new(media_path)),
+        db_pool: db_pool.clone(),
+        config: stowage::Config {
+            auth: stowage::AuthConfig { enabled: false },
+            ..Default::default()
+        },
+        worker: None,
+    }
+}
+
 #[cfg(test)] 
 fn init_test_logger() {
     let _ = env_logger::builder().is_test(true).try_init(); 
@@ -196,11 +211,7 @@ async fn test_upload_same_filename_different_content() {
     }
     let app = test::init_service(
         App::new()
-            .app_data(actix_web::web::Data::new(stowage::AppState {
-                media_path: media_path.path().to_path_buf(),
-                db_pool: db_pool.clone(),
-                worker: None
-            }))
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
             .configure(stowage::routes),
     )
     .await;
@@ -227,7 +238,7 @@ async fn test_upload_same_filename_different_content() {
     assert_eq!(resp1.status(), 201, "First file should upload successfully");
     let body1 = test::read_body(resp1).await;
     let resp_json1: serde_json::Value = serde_json::from_slice(&body1).unwrap();
-    let download_url1 = resp_json1["download_url"].as_str().unwrap().to_string();
+    let download_url1 = resp_json1[0]["download_url"].as_str().unwrap().to_string();
     
     // Upload second file with same name but different content
     let boundary2 = "BOUNDARY2";
@@ -241,7 +252,7 @@ async fn test_upload_same_filename_different_content() {
     assert_eq!(resp2.status(), 201, "Second file should upload successfully");
     let body2 = test::read_body(resp2).await;
     let resp_json2: serde_json::Value = serde_json::from_slice(&body2).unwrap();
-    let download_url2 = resp_json2["download_url"].as_str().unwrap().to_string();
+    let download_url2 = resp_json2[0]["download_url"].as_str().unwrap().to_string();
     
     // Verify different URLs were generated
     assert_ne!(
@@ -265,11 +276,7 @@ async fn test_upload_same_content_different_filename() {
     }
     let app = test::init_service(
         App::new()
-            .app_data(actix_web::web::Data::new(stowage::AppState {
-                media_path: media_path.path().to_path_buf(),
-                db_pool: db_pool.clone(),
-                worker: None
-            }))
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
             .configure(stowage::routes),
     )
     .await;
@@ -292,8 +299,8 @@ async fn test_upload_same_content_different_filename() {
     assert_eq!(resp1.status(), 201, "First file should upload successfully");
     let body1 = test::read_body(resp1).await;
     let resp_json1: serde_json::Value = serde_json::from_slice(&body1).unwrap();
-    let download_url1 = resp_json1["download_url"].as_str().unwrap().to_string();
-    let file_id1 = resp_json1["file_id"].as_str().unwrap().to_string();
+    let download_url1 = resp_json1[0]["download_url"].as_str().unwrap().to_string();
+    let file_id1 = resp_json1[0]["file_id"].as_str().unwrap().to_string();
     
     // Get the count of records before second upload
     let conn = db_pool.get().unwrap();
@@ -312,34 +319,37 @@ async fn test_upload_same_content_different_filename() {
         .set_payload(body2)
         .to_request();
     let resp2 = test::call_service(&app, req2).await;
-    assert_eq!(resp2.status(), 200, "Duplicate content should return 200 with original URL");
+    assert_eq!(resp2.status(), 201, "Duplicate content is still a new upload");
     let body2 = test::read_body(resp2).await;
     let resp_json2: serde_json::Value = serde_json::from_slice(&body2).unwrap();
-    let download_url2 = resp_json2["download_url"].as_str().unwrap().to_string();
-    
-    // Verify the same URL is returned
-    assert_eq!(
-        download_url1, download_url2,
-        "Same content should return the same download URL regardless of filename"
-    );
+    let download_url2 = resp_json2[0]["download_url"].as_str().unwrap().to_string();
+    let file_id2 = resp_json2[0]["file_id"].as_str().unwrap().to_string();
+    assert_eq!(resp_json1[0]["deduplicated"], false);
+    assert_eq!(resp_json2[0]["deduplicated"], true);
     
-    // Verify no new record was added to the database
+    // Each upload gets its own ID, URL and metadata
+    assert_ne!(file_id1, file_id2, "Each upload should get its own file_id");
+    assert_ne!(download_url1, download_url2);
     let count_after: i64 = conn.query_row(
         "SELECT COUNT(*) FROM File",
         [],
         |row| row.get(0)
     ).unwrap();
-    assert_eq!(
-        count_before, count_after,
-        "No new database record should be created for duplicate content"
-    );
+    assert_eq!(count_after, count_before + 1, "A new file record should be created");
+    let file1 = stowage::db_utils::get_file_by_uuid(&conn, &file_id1).unwrap().unwrap();
+    let file2 = stowage::db_utils::get_file_by_uuid(&conn, &file_id2).unwrap().unwrap();
+    assert_eq!(file1.original_filename.as_deref(), Some("original.json"));
+    assert_eq!(file2.original_filename.as_deref(), Some("duplicate.json"));
     
-    // Verify the file_id in the response matches the first upload
-    let file_id2 = resp_json2["file_id"].as_str().unwrap().to_string();
-    assert_eq!(
-        file_id1, file_id2,
-        "Same file_id should be returned for duplicate content"
-    );
+    // ...but the bytes are stored once
+    assert_eq!(file1.blob_id, file2.blob_id);
+    assert_eq!(file1.filepath, file2.filepath);
+    assert_eq!(file2.ref_count, 2);
+    let blobs: i64 = conn.query_row("SELECT COUNT(*) FROM Blob", [], |row| row.get(0)).unwrap();
+    assert_eq!(blobs, 1, "Duplicate content should not be stored twice");
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&download_url2).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(test::read_body(resp).await, file_bytes);
 }
 
 #[actix_web::test]
@@ -359,11 +369,7 @@ async fn test_download_job_creation() {
     // Create test app
     let app = test::init_service(
         App::new()
-            .app_data(actix_web::web::Data::new(stowage::AppState {
-                media_path: media_path.path().to_path_buf(),
-                db_pool: db_pool.clone(),
-                worker: None
-            }))
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
             .configure(stowage::routes),
     )
     .await;
@@ -374,7 +380,7 @@ async fn test_download_job_creation() {
     // 1. Make a request to create a download job
     let req = test::TestRequest::post()
         .uri("/download")
-        .set_json(&json!({"download_url": test_url}))
+        .set_json(json!({"download_url": test_url}))
         .to_request();
     let resp = test::call_service(&app, req).await;
     
@@ -409,6 +415,1367 @@ async fn test_download_job_creation() {
     assert_eq!(status_body["file_id"], serde_json::Value::Null);
 }
 
+#[actix_web::test]
+async fn test_upload_uses_sharded_layout_and_index() {
+    init_test_logger();
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    {
+        let conn = db_pool.get().unwrap();
+        stowage::db_utils::init_db(&conn).unwrap();
+    }
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let boundary = "XBOUNDARY";
+    let req = test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", format!("multipart/form-data; boundary={}", boundary)))
+        .set_payload(build_multipart_body("file", "example.png", &file_bytes, boundary))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+    let resp_json: serde_json::Value = test::read_body_json(resp).await;
+    let file_id = resp_json[0]["file_id"].as_str().unwrap();
+
+    // Stored under ab/cd/<uuid>.png and resolvable through the File table
+    let conn = db_pool.get().unwrap();
+    let file = stowage::db_utils::get_file_by_uuid(&conn, file_id).unwrap().expect("File row missing");
+    assert_eq!(file.filepath, format!("{}/{}/{}.png", &file_id[..2], &file_id[2..4], file_id));
+    assert!(media_path.path().join(&file.filepath).exists());
+    assert!(!media_path.path().join("tmp").read_dir().unwrap().any(|_| true), "Temp area should be empty");
+
+    // A file on disk without a File row is not served
+    fs::write(media_path.path().join("stray.json"), b"{}").unwrap();
+    let req = test::TestRequest::get().uri("/files/stray").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_upload_over_type_size_limit_is_rejected() {
+    init_test_logger();
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    {
+        let conn = db_pool.get().unwrap();
+        stowage::db_utils::init_db(&conn).unwrap();
+    }
+    // JSON is capped at 16 bytes, everything else keeps the default limit
+    let mut state = test_app_state(media_path.path(), &db_pool);
+    state.config.max_file_size_by_type = std::collections::HashMap::from([
+        ("application/json".to_string(), 16),
+    ]);
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(state))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    let data_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data");
+    let boundary = "XBOUNDARY";
+    let json_bytes = fs::read(data_dir.join("example.json")).unwrap();
+    let req = test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", format!("multipart/form-data; boundary={}", boundary)))
+        .set_payload(build_multipart_body("file", "example.json", &json_bytes, boundary))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(!media_path.path().join("tmp").read_dir().unwrap().any(|_| true), "Temp file should be removed");
+
+    let png_bytes = fs::read(data_dir.join("example.png")).unwrap();
+    let req = test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", format!("multipart/form-data; boundary={}", boundary)))
+        .set_payload(build_multipart_body("file", "example.png", &png_bytes, boundary))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn test_upload_respects_configured_mime_types() {
+    init_test_logger();
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    {
+        let conn = db_pool.get().unwrap();
+        stowage::db_utils::init_db(&conn).unwrap();
+    }
+    let mut state = test_app_state(media_path.path(), &db_pool);
+    state.config.allowed_mime_types = vec!["image/*".to_string()];
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(state))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    let data_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data");
+    let boundary = "XBOUNDARY";
+    for (name, expected) in [("example.png", StatusCode::CREATED), ("example.json", StatusCode::BAD_REQUEST)] {
+        let bytes = fs::read(data_dir.join(name)).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("content-type", format!("multipart/form-data; boundary={}", boundary)))
+            .set_payload(build_multipart_body("file", name, &bytes, boundary))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), expected, "Unexpected status for {}", name);
+    }
+}
+
+#[actix_web::test]
+async fn test_cancel_job_and_failed_status() {
+    init_test_logger();
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    {
+        let conn = db_pool.get().unwrap();
+        stowage::db_utils::init_db(&conn).unwrap();
+    }
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    // Cancel a queued job
+    let req = test::TestRequest::post()
+        .uri("/download")
+        .set_json(json!({"download_url": "https://example.com/a.json"}))
+        .to_request();
+    let body: stowage::handlers::DownloadResponse = test::read_body_json(test::call_service(&app, req).await).await;
+    let job_uri = format!("/jobs/{}", body.job_id);
+
+    let resp = test::call_service(&app, test::TestRequest::delete().uri(&job_uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let status: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&job_uri).to_request()).await;
+    assert_eq!(status["status"], "Cancelled");
+
+    // Cancelling again conflicts, unknown jobs are not found
+    let resp = test::call_service(&app, test::TestRequest::delete().uri(&job_uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let resp = test::call_service(&app, test::TestRequest::delete().uri("/jobs/does-not-exist").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // A failed job is reported as Failed with its error, not as NotStarted
+    let conn = db_pool.get().unwrap();
+    stowage::db_utils::insert_job(&conn, "failing-job", &stowage::db_utils::JobStatus::NotStarted, None, "https://example.com/b.json", 1).unwrap();
+    let job = stowage::db_utils::get_and_start_job(&conn, std::time::Duration::from_secs(60)).unwrap().expect("Job should start");
+    assert_eq!(job.id, "failing-job");
+    assert!(stowage::db_utils::fail_job(&conn, "failing-job", "Failed to download file: 404 Not Found").unwrap());
+    let status: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/jobs/failing-job").to_request()).await;
+    assert_eq!(status["status"], "Failed");
+    assert_eq!(status["error"], "Failed to download file: 404 Not Found");
+}
+
+#[actix_web::test]
+async fn test_retrying_job_backoff_and_attempt_log() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let mut state = test_app_state(media_path.path(), &db_pool);
+    state.config.retry.max_attempts = 3;
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(state))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    // Requested attempts are capped by the retry policy
+    let req = test::TestRequest::post()
+        .uri("/download")
+        .set_json(json!({"download_url": "https://example.com/a.json", "max_attempts": 10}))
+        .to_request();
+    let body: stowage::handlers::DownloadResponse = test::read_body_json(test::call_service(&app, req).await).await;
+    let job_uri = format!("/jobs/{}", body.job_id);
+
+    let conn = db_pool.get().unwrap();
+    let job = stowage::db_utils::get_and_start_job(&conn, std::time::Duration::from_secs(60)).unwrap().expect("Job should start");
+    assert_eq!((job.attempts, job.max_attempts), (1, 3));
+
+    // A job backing off is not picked up until its next attempt is due
+    assert!(stowage::db_utils::retry_job(&conn, &job.id, "Failed to download file: 503 Service Unavailable", std::time::Duration::from_secs(60)).unwrap());
+    assert!(stowage::db_utils::get_and_start_job(&conn, std::time::Duration::from_secs(60)).unwrap().is_none());
+    let status: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&job_uri).to_request()).await;
+    assert_eq!(status["status"], "Retrying");
+    assert_eq!(status["attempts"], 1);
+    assert!(status["next_attempt_at"].is_string());
+    assert_eq!(status["attempt_log"][0]["error"], "Failed to download file: 503 Service Unavailable");
+    assert!(status["attempt_log"][0]["finished_at"].is_string());
+
+    conn.execute("UPDATE Job SET next_attempt_at = datetime('now', '-1 seconds') WHERE id = ?1", [&job.id]).unwrap();
+    let job = stowage::db_utils::get_and_start_job(&conn, std::time::Duration::from_secs(60)).unwrap().expect("Retry should be due");
+    assert_eq!(job.attempts, 2);
+    let blob_id = stowage::db_utils::insert_blob(&conn, "abc", "re/tr/retried-file.json", 2).unwrap();
+    let file_id = stowage::db_utils::insert_file(&conn, &stowage::db_utils::NewFile {
+        uuid: "retried-file".into(),
+        blob_id,
+        url: "/files/retried-file".into(),
+        ..Default::default()
+    }).unwrap();
+    assert!(stowage::db_utils::complete_job(&conn, &job.id, file_id).unwrap());
+    let status: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&job_uri).to_request()).await;
+    assert_eq!(status["status"], "Completed");
+    assert_eq!(status["attempt_log"].as_array().unwrap().len(), 2);
+    assert!(status["attempt_log"][1]["error"].is_null());
+}
+
+#[actix_web::test]
+async fn test_serve_file_etag_conditional_and_ranges() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let req = test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .set_payload(build_multipart_body("file", "example.png", &file_bytes, "XBOUNDARY"))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let uri = format!("/files/{}", body[0]["file_id"].as_str().unwrap());
+    let etag = format!("\"{:x}\"", Sha256::digest(&file_bytes));
+
+    // Strong ETag from the content hash; caches revalidate against it
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("etag").unwrap().to_str().unwrap(), etag);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "public, no-cache");
+    assert_eq!(resp.headers().get("accept-ranges").unwrap(), "bytes");
+    assert_eq!(test::read_body(resp).await, file_bytes);
+
+    // The content's own URL never changes meaning, so it is cached for good
+    let blob_uri = format!("/blobs/{:x}", Sha256::digest(&file_bytes));
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&blob_uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "public, max-age=31536000, immutable");
+    assert_eq!(resp.headers().get("etag").unwrap().to_str().unwrap(), etag);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    assert_eq!(test::read_body(resp).await, file_bytes);
+    for missing in [format!("/blobs/{:x}", Sha256::digest(b"other")), "/blobs/not-a-hash".to_string()] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&missing).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", missing);
+    }
+
+    let get = |headers: Vec<(&'static str, String)>| {
+        let mut req = test::TestRequest::get().uri(&uri);
+        for header in headers {
+            req = req.insert_header(header);
+        }
+        req.to_request()
+    };
+    let resp = test::call_service(&app, get(vec![("if-none-match", etag.clone())])).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    let resp = test::call_service(&app, get(vec![("if-match", "\"stale\"".into())])).await;
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+
+    // Single range
+    let resp = test::call_service(&app, get(vec![("range", "bytes=10-19".into())])).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.headers().get("content-range").unwrap().to_str().unwrap(), format!("bytes 10-19/{}", file_bytes.len()));
+    assert_eq!(test::read_body(resp).await, file_bytes[10..20]);
+
+    // Overlapping ranges are merged into one multipart/byteranges body
+    let resp = test::call_service(&app, get(vec![("range", "bytes=0-3,2-5,-4".into())])).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    let content_type = resp.headers().get("content-type").unwrap().to_str().unwrap().to_string();
+    let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").expect("Multipart response");
+    let length: usize = resp.headers().get("content-length").unwrap().to_str().unwrap().parse().unwrap();
+    let body = test::read_body(resp).await;
+    assert_eq!(body.len(), length);
+    let text = String::from_utf8_lossy(&body);
+    assert_eq!(text.matches(&format!("--{}\r\n", boundary)).count(), 2);
+    assert!(text.contains("Content-Range: bytes 0-5/"));
+    assert!(body.windows(6).any(|w| w == &file_bytes[..6]));
+    assert!(body.windows(4).any(|w| w == &file_bytes[file_bytes.len() - 4..]));
+
+    // Unsatisfiable ranges, and a stale If-Range falls back to the full file
+    let resp = test::call_service(&app, get(vec![("range", format!("bytes={}-", file_bytes.len()))])).await;
+    assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    let resp = test::call_service(&app, get(vec![("range", "bytes=0-9".into()), ("if-range", "\"stale\"".into())])).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_delete_file_reference_counting() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.json")).unwrap();
+    let upload = || test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .set_payload(build_multipart_body("file", "example.json", &file_bytes, "XBOUNDARY"))
+        .to_request();
+    let first: serde_json::Value = test::call_and_read_body_json(&app, upload()).await;
+    let second: serde_json::Value = test::call_and_read_body_json(&app, upload()).await;
+    let first_uri = format!("/files/{}", first[0]["file_id"].as_str().unwrap());
+    let file_id = second[0]["file_id"].as_str().unwrap();
+    let uri = format!("/files/{}", file_id);
+    let conn = db_pool.get().unwrap();
+    let file = stowage::db_utils::get_file_by_uuid(&conn, file_id).unwrap().unwrap();
+    assert_eq!(file.ref_count, 2);
+
+    // A finished job pointing at the file doesn't block deletion
+    stowage::db_utils::insert_job(&conn, "done-job", &stowage::db_utils::JobStatus::NotStarted, None, "https://example.com/a.json", 1).unwrap();
+    stowage::db_utils::get_and_start_job(&conn, std::time::Duration::from_secs(60)).unwrap().unwrap();
+    stowage::db_utils::complete_job(&conn, "done-job", file.id).unwrap();
+
+    // Deleting one upload leaves the other, and the shared bytes, in place
+    let resp: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::delete().uri(&first_uri).to_request()).await;
+    assert_eq!(resp["references_remaining"], 1);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&first_uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // If the stored object can't be removed, nothing changes in the DB
+    let blob_path = media_path.path().join(&file.filepath);
+    let moved_path = media_path.path().join("moved-away");
+    fs::rename(&blob_path, &moved_path).unwrap();
+    let resp = test::call_service(&app, test::TestRequest::delete().uri(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(stowage::db_utils::get_file_by_uuid(&conn, file_id).unwrap().unwrap().ref_count, 1);
+    fs::rename(&moved_path, &blob_path).unwrap();
+
+    // The last reference removes the row and the stored object
+    let resp: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::delete().uri(&uri).to_request()).await;
+    assert_eq!(resp["references_remaining"], 0);
+    assert!(!blob_path.exists());
+    assert!(stowage::db_utils::get_file_by_uuid(&conn, file_id).unwrap().is_none());
+    let blobs: i64 = conn.query_row("SELECT COUNT(*) FROM Blob", [], |row| row.get(0)).unwrap();
+    assert_eq!(blobs, 0);
+    assert_eq!(stowage::db_utils::get_job_by_id(&conn, "done-job").unwrap().unwrap().file_id, None);
+    let tmp_dir = media_path.path().join("tmp");
+    assert!(!tmp_dir.exists() || fs::read_dir(&tmp_dir).unwrap().next().is_none());
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = test::call_service(&app, test::TestRequest::delete().uri(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_file_metadata_and_head() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let req = test::TestRequest::post()
+        .uri("/upload?tags=cover,art")
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .set_payload(build_multipart_body("file", "example.png", &file_bytes, "XBOUNDARY"))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let file_id = body[0]["file_id"].as_str().unwrap();
+    let uri = format!("/files/{}", file_id);
+
+    let meta: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("{}/meta", uri)).to_request()).await;
+    assert_eq!(meta["file_id"], file_id);
+    assert_eq!(meta["download_url"], uri);
+    assert_eq!(meta["original_filename"], "example.png");
+    assert_eq!(meta["mime_type"], "image/png");
+    assert_eq!(meta["size"], file_bytes.len());
+    assert_eq!(meta["sha256"], format!("{:x}", Sha256::digest(&file_bytes)));
+    assert!(meta["created_at"].is_string());
+    assert_eq!(meta["deduplicated"], false);
+    assert!(meta["source_url"].is_null());
+    assert_eq!(meta["tags"], serde_json::json!(["art", "cover"]));
+    let expected = stowage::media_info::extract(&file_bytes, "image/png").unwrap();
+    assert_eq!(meta["media_info"]["width"], expected.width.unwrap());
+    assert_eq!(meta["media_info"]["height"], expected.height.unwrap());
+
+    // HEAD sends the headers of a GET without the body
+    let get = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    let head = test::call_service(&app, test::TestRequest::default().method(actix_web::http::Method::HEAD).uri(&uri).to_request()).await;
+    assert_eq!(head.status(), StatusCode::OK);
+    for name in ["etag", "content-type", "content-length", "cache-control", "accept-ranges", "content-disposition"] {
+        assert_eq!(head.headers().get(name), get.headers().get(name), "{} differs", name);
+    }
+    assert_eq!(head.headers().get("content-length").unwrap().to_str().unwrap(), file_bytes.len().to_string());
+    assert!(test::read_body(head).await.is_empty());
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/files/missing/meta").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = test::call_service(&app, test::TestRequest::default().method(actix_web::http::Method::HEAD).uri("/files/missing").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_list_files_filters_sorting_and_pagination() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    let conn = db_pool.get().unwrap();
+    stowage::db_utils::init_db(&conn).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    let files = [
+        ("a", "Cover.png", "image/png", 100, "2024-01-01 10:00:00", vec!["art"]),
+        ("b", "episode1.mp3", "audio/mpeg", 5000, "2024-02-01 10:00:00", vec!["podcast"]),
+        ("c", "episode2.mp3", "audio/mpeg", 7000, "2024-03-01 10:00:00", vec!["podcast", "art"]),
+        ("d", "feed.json", "application/json", 300, "2024-04-01 10:00:00", vec![]),
+        ("e", "banner.jpg", "image/jpeg", 2000, "2024-05-01 10:00:00", vec![]),
+    ];
+    for (uuid, name, mime, size, created_at, tags) in files {
+        let blob_id = stowage::db_utils::insert_blob(&conn, uuid, uuid, size).unwrap();
+        let id = stowage::db_utils::insert_file(&conn, &stowage::db_utils::NewFile {
+            uuid: uuid.to_string(),
+            blob_id,
+            url: format!("/files/{}", uuid),
+            original_filename: Some(name.to_string()),
+            mime_type: Some(mime.to_string()),
+            tags: tags.into_iter().map(String::from).collect(),
+            ..Default::default()
+        }).unwrap();
+        conn.execute("UPDATE File SET created_at = ?1 WHERE id = ?2", rusqlite::params![created_at, id]).unwrap();
+    }
+
+    let list = |query: &str| {
+        let req = test::TestRequest::get().uri(&format!("/files?{}", query)).to_request();
+        test::call_and_read_body_json::<_, _, serde_json::Value>(&app, req)
+    };
+    let ids = |page: &serde_json::Value| -> Vec<String> {
+        page["files"].as_array().unwrap().iter().map(|f| f["file_id"].as_str().unwrap().to_string()).collect()
+    };
+
+    // Newest first by default
+    let page = list("").await;
+    assert_eq!(ids(&page), ["e", "d", "c", "b", "a"]);
+    assert!(page["next_cursor"].is_null());
+    assert_eq!(page["files"][2]["tags"], serde_json::json!(["art", "podcast"]));
+
+    assert_eq!(ids(&list("mime=image").await), ["e", "a"]);
+    assert_eq!(ids(&list("mime=audio/mpeg&sort=size").await), ["b", "c"]);
+    assert_eq!(ids(&list("min_size=300&max_size=5000&sort=size&order=desc").await), ["b", "e", "d"]);
+    assert_eq!(ids(&list("created_after=2024-02-01&created_before=2024-04-01").await), ["d", "c", "b"]);
+    assert_eq!(ids(&list("created_after=2024-03-01T11:00:00%2B01:00").await), ["e", "d", "c"]);
+    assert_eq!(ids(&list("filename=EPISODE&sort=filename").await), ["b", "c"]);
+    assert_eq!(ids(&list("tag=art&sort=filename").await), ["a", "c"]);
+    assert_eq!(ids(&list("tag=art&mime=audio").await), ["c"]);
+
+    // Walk all files two at a time
+    let mut seen = Vec::new();
+    let mut query = "sort=filename&limit=2".to_string();
+    loop {
+        let page = list(&query).await;
+        let page_ids = ids(&page);
+        assert!(page_ids.len() <= 2);
+        seen.extend(page_ids);
+        match page["next_cursor"].as_str() {
+            Some(cursor) => query = format!("sort=filename&limit=2&cursor={}", cursor),
+            None => break,
+        }
+    }
+    assert_eq!(seen, ["e", "a", "b", "c", "d"]);
+
+    // Cursors only continue the listing they came from
+    let cursor = list("limit=1").await["next_cursor"].as_str().unwrap().to_string();
+    assert_eq!(ids(&list(&format!("limit=1&cursor={}", cursor)).await), ["d"]);
+    for query in [format!("sort=size&cursor={}", cursor), "cursor=garbage".to_string(), "created_after=yesterday".to_string(), "sort=color".to_string()] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/files?{}", query)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", query);
+    }
+}
+
+#[actix_web::test]
+async fn test_api_key_scopes_and_public_files() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    let conn = db_pool.get().unwrap();
+    stowage::db_utils::init_db(&conn).unwrap();
+    let mut state = test_app_state(media_path.path(), &db_pool);
+    state.config.auth.enabled = true;
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(state))
+            .configure(stowage::routes),
+    )
+    .await;
+    use stowage::auth::Scope;
+    let (_, admin) = stowage::auth::mint_key(&conn, "admin", &[Scope::Admin]).unwrap();
+    let (uploader_id, uploader) = stowage::auth::mint_key(&conn, "uploader", &[Scope::Upload]).unwrap();
+
+    let call = |method: actix_web::http::Method, uri: &str, key: Option<&str>| {
+        let mut req = test::TestRequest::default().method(method).uri(uri);
+        if let Some(key) = key {
+            req = req.insert_header(("authorization", format!("Bearer {}", key)));
+        }
+        req
+    };
+    use actix_web::http::Method;
+
+    // Anonymous requests only reach /about
+    let resp = test::call_service(&app, call(Method::GET, "/files", None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.headers().get("www-authenticate").unwrap(), "Bearer");
+    let resp = test::call_service(&app, call(Method::GET, "/files", Some("stw_bogus")).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = test::call_service(&app, call(Method::GET, "/about", None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Each key can do what its scopes allow
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let upload = |key: Option<&str>| call(Method::POST, "/upload", key)
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .insert_header(("x-forwarded-for", "203.0.113.7"))
+        .set_payload(build_multipart_body("file", "example.png", &file_bytes, "XBOUNDARY"))
+        .to_request();
+    let resp = test::call_service(&app, upload(None)).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: serde_json::Value = test::call_and_read_body_json(&app, upload(Some(&uploader))).await;
+    let uri = format!("/files/{}", body[0]["file_id"].as_str().unwrap());
+    // The uploader is the key, not an address the client claims
+    let recorded: String = conn.query_row("SELECT uploader FROM File WHERE uuid = ?1", [body[0]["file_id"].as_str().unwrap()], |row| row.get(0)).unwrap();
+    assert_eq!(recorded, format!("key:{}", uploader_id));
+    let resp = test::call_service(&app, call(Method::GET, &uri, Some(&uploader)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = test::call_service(&app, call(Method::POST, "/admin/keys", Some(&uploader)).set_json(serde_json::json!({"name": "x", "scopes": ["admin"]})).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Admins mint and revoke keys
+    let minted: serde_json::Value = test::call_and_read_body_json(&app, call(Method::POST, "/admin/keys", Some(&admin)).set_json(serde_json::json!({"name": "reader", "scopes": ["read"]})).to_request()).await;
+    let reader = minted["key"].as_str().unwrap().to_string();
+    assert!(reader.starts_with("stw_"));
+    let resp = test::call_service(&app, call(Method::GET, &uri, None).insert_header(("x-api-key", reader.clone())).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("cache-control").unwrap().to_str().unwrap().starts_with("private"));
+    let keys: serde_json::Value = test::call_and_read_body_json(&app, call(Method::GET, "/admin/keys", Some(&admin)).to_request()).await;
+    assert_eq!(keys.as_array().unwrap().len(), 3);
+    assert!(!keys.to_string().contains(&reader), "keys are never listed");
+    let resp = test::call_service(&app, call(Method::DELETE, &format!("/admin/keys/{}", minted["id"]), Some(&admin)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, call(Method::GET, &uri, Some(&reader)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Public files can be read by anyone, other routes still need a key
+    let resp = test::call_service(&app, call(Method::GET, &format!("{}/meta", uri), None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let blob_uri = format!("/blobs/{:x}", Sha256::digest(&file_bytes));
+    let resp = test::call_service(&app, call(Method::GET, &blob_uri, None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = test::call_service(&app, call(Method::GET, &blob_uri, Some(&admin)).to_request()).await;
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "private, max-age=31536000, immutable");
+    let meta: serde_json::Value = test::call_and_read_body_json(&app, call(Method::PATCH, &uri, Some(&admin)).set_json(serde_json::json!({"public": true})).to_request()).await;
+    assert_eq!(meta["public"], true);
+    let resp = test::call_service(&app, call(Method::GET, &uri, None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("cache-control").unwrap().to_str().unwrap().starts_with("public"));
+    let resp = test::call_service(&app, call(Method::GET, &blob_uri, None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, call(Method::GET, &format!("{}/meta", uri), None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, call(Method::DELETE, &uri, None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = test::call_service(&app, call(Method::GET, "/files/missing", None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_signed_urls() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    let conn = db_pool.get().unwrap();
+    stowage::db_utils::init_db(&conn).unwrap();
+    let signing_key = |id: &str| stowage::SigningKey { id: id.into(), secret: format!("{}-secret-0123456789abcdef0123456789", id) };
+    let mut state = test_app_state(media_path.path(), &db_pool);
+    state.config.auth.enabled = true;
+    state.config.signed_urls.keys = vec![signing_key("new"), signing_key("old")];
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(state))
+            .configure(stowage::routes),
+    )
+    .await;
+    let (_, key) = stowage::auth::mint_key(&conn, "app", &[stowage::auth::Scope::Upload, stowage::auth::Scope::Read]).unwrap();
+    let bearer = ("authorization", format!("Bearer {}", key));
+
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let req = test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(bearer.clone())
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .set_payload(build_multipart_body("file", "example.png", &file_bytes, "XBOUNDARY"))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let file_id = body[0]["file_id"].as_str().unwrap().to_string();
+    let sign = |request: serde_json::Value| test::TestRequest::post()
+        .uri(&format!("/files/{}/signed-url", file_id))
+        .insert_header(bearer.clone())
+        .set_json(request)
+        .to_request();
+    let path_of = |signed: &serde_json::Value| {
+        let url = signed["url"].as_str().unwrap();
+        url[url.find("/files/").unwrap()..].to_string()
+    };
+    let peer = |ip: &str| format!("{}:4000", ip).parse::<std::net::SocketAddr>().unwrap();
+
+    // Anyone holding the URL can read the file, as an attachment if asked
+    let signed: serde_json::Value = test::call_and_read_body_json(&app, sign(serde_json::json!({
+        "expires_in": 60, "disposition": "attachment", "filename": "cover art.png"
+    }))).await;
+    assert!(signed["expires_at"].is_string());
+    let path = path_of(&signed);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&path).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let disposition = resp.headers().get("content-disposition").unwrap().to_str().unwrap().to_string();
+    assert!(disposition.starts_with("attachment") && disposition.contains("cover art.png"), "{}", disposition);
+    assert!(resp.headers().get("cache-control").unwrap().to_str().unwrap().starts_with("private"));
+    assert_eq!(test::read_body(resp).await, file_bytes);
+
+    // Changing anything breaks the signature
+    for tampered in [path.replace("attachment", "inline"), path.replace("expires=", "expires=9"), format!("{}x", path)] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&tampered).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", tampered);
+    }
+
+    // Bound to one client address
+    let signed: serde_json::Value = test::call_and_read_body_json(&app, sign(serde_json::json!({"ip": "203.0.113.7"}))).await;
+    let path = path_of(&signed);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&path).peer_addr(peer("203.0.113.8")).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&path).peer_addr(peer("203.0.113.7")).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // URLs signed with a rotated-out key keep working while it is configured,
+    // and expired ones never do
+    let grant = |expires: i64| stowage::signed_url::Grant { file_id: file_id.clone(), expires, ip: None, disposition: None, filename: None };
+    let now = chrono::Utc::now().timestamp();
+    let old = stowage::signed_url::sign(&signing_key("old"), &grant(now + 60));
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&old).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let retired = stowage::signed_url::sign(&signing_key("retired"), &grant(now + 60));
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&retired).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let expired = stowage::signed_url::sign(&signing_key("new"), &grant(now - 1));
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&expired).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = test::call_service(&app, sign(serde_json::json!({"expires_in": 365 * 24 * 3600}))).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = test::call_service(&app, test::TestRequest::post().uri(&format!("/files/{}/signed-url", file_id)).set_json(serde_json::json!({})).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_cors_policies_per_route() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let frontend = "https://app.example.com";
+    let cors: stowage::CorsConfig = toml::from_str(&format!(r#"
+[[routes]]
+path = "/upload"
+allowed_origins = ["{}"]
+block_other_origins = true
+"#, frontend)).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(|cfg| stowage::config_with(&cors, cfg)),
+    )
+    .await;
+
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let upload = |origin: &str| test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("origin", origin))
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .set_payload(build_multipart_body("file", "example.png", &file_bytes, "XBOUNDARY"))
+        .to_request();
+    let preflight = |origin: &str, uri: &str, method: &str| test::TestRequest::default()
+        .method(actix_web::http::Method::OPTIONS)
+        .uri(uri)
+        .insert_header(("origin", origin))
+        .insert_header(("access-control-request-method", method))
+        .to_request();
+
+    // Uploads only from the frontend
+    let resp = test::call_service(&app, upload("https://evil.example")).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = test::call_service(&app, preflight("https://evil.example", "/upload", "POST")).await;
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+    let resp = test::call_service(&app, preflight(frontend, "/upload", "POST")).await;
+    assert_eq!(resp.headers().get("access-control-allow-origin").unwrap(), frontend);
+    let resp = test::call_service(&app, upload(frontend)).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(resp.headers().get("access-control-allow-origin").unwrap(), frontend);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+
+    // Reads stay open to any site, which can see the range and cache headers
+    let resp = test::call_service(&app, test::TestRequest::get()
+        .uri(&format!("/files/{}", body[0]["file_id"].as_str().unwrap()))
+        .insert_header(("origin", "https://evil.example"))
+        .insert_header(("range", "bytes=0-9"))
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.headers().get("access-control-allow-origin").unwrap(), "https://evil.example");
+    let exposed = resp.headers().get("access-control-expose-headers").unwrap().to_str().unwrap().to_ascii_lowercase();
+    assert!(exposed.contains("content-range") && exposed.contains("etag"), "{}", exposed);
+    let resp = test::call_service(&app, preflight("https://evil.example", "/files", "GET")).await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_download_rejects_unsafe_urls() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let mut state = test_app_state(media_path.path(), &db_pool);
+    state.config.fetch.denied_hosts = vec!["*.internal.example".into()];
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(state))
+            .configure(stowage::config),
+    )
+    .await;
+
+    let download = |url: &str| test::TestRequest::post()
+        .uri("/download")
+        .set_json(serde_json::json!({ "download_url": url }))
+        .to_request();
+    for url in [
+        "http://169.254.169.254/latest/meta-data/",
+        "http://127.0.0.1:8080/admin",
+        "http://[::ffff:10.0.0.1]/",
+        "file:///etc/passwd",
+        "gopher://example.com/",
+        "https://db.internal.example/dump",
+        "not a url",
+    ] {
+        let resp = test::call_service(&app, download(url)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{} should be refused", url);
+    }
+    let conn = db_pool.get().unwrap();
+    let jobs: i64 = conn.query_row("SELECT COUNT(*) FROM Job", [], |row| row.get(0)).unwrap();
+    assert_eq!(jobs, 0, "Refused URLs must not be queued");
+
+    // Names are only checked when the worker resolves them
+    let resp = test::call_service(&app, download("https://example.com/a.png")).await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+}
+
+#[actix_web::test]
+async fn test_resumable_uploads() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::config),
+    )
+    .await;
+    let header = |resp: &actix_web::dev::ServiceResponse, name: &str| {
+        resp.headers().get(name).map(|v| v.to_str().unwrap().to_string())
+    };
+
+    let resp = test::call_service(&app, test::TestRequest::default()
+        .method(actix_web::http::Method::OPTIONS)
+        .uri("/uploads")
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "tus-version").as_deref(), Some("1.0.0"));
+    assert!(header(&resp, "tus-extension").unwrap().contains("creation"));
+
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let create = || test::TestRequest::post()
+        .uri("/uploads")
+        .insert_header(("tus-resumable", "1.0.0"))
+        .insert_header(("upload-length", file_bytes.len().to_string()))
+        // filename "photo.png", tags "holiday, beach"
+        .insert_header(("upload-metadata", "filename cGhvdG8ucG5n,tags aG9saWRheSwgYmVhY2g="));
+    let resp = test::call_service(&app, test::TestRequest::post()
+        .uri("/uploads")
+        .insert_header(("upload-length", "10"))
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED, "Tus-Resumable is required");
+    let resp = test::call_service(&app, create().to_request()).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(header(&resp, "tus-resumable").as_deref(), Some("1.0.0"));
+    assert!(header(&resp, "upload-expires").unwrap().ends_with(" GMT"));
+    let location = header(&resp, "location").unwrap();
+    let upload_uri = &location[location.find("/uploads/").unwrap()..];
+    let upload_id = upload_uri.trim_start_matches("/uploads/").to_string();
+
+    let status = || test::TestRequest::default()
+        .method(actix_web::http::Method::HEAD)
+        .uri(upload_uri)
+        .insert_header(("tus-resumable", "1.0.0"))
+        .to_request();
+    let patch = |offset: usize, body: &[u8]| test::TestRequest::patch()
+        .uri(upload_uri)
+        .insert_header(("tus-resumable", "1.0.0"))
+        .insert_header(("upload-offset", offset.to_string()))
+        .insert_header(("content-type", "application/offset+octet-stream"))
+        .set_payload(body.to_vec())
+        .to_request();
+    let resp = test::call_service(&app, status()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "upload-offset").as_deref(), Some("0"));
+    assert_eq!(header(&resp, "upload-length"), Some(file_bytes.len().to_string()));
+    assert_eq!(header(&resp, "cache-control").as_deref(), Some("no-store"));
+
+    // Send the file in two pieces, with a stale offset in between
+    let half = file_bytes.len() / 2;
+    let resp = test::call_service(&app, patch(0, &file_bytes[..half])).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "upload-offset"), Some(half.to_string()));
+    let resp = test::call_service(&app, patch(0, &file_bytes[..half])).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let resp = test::call_service(&app, test::TestRequest::patch()
+        .uri(upload_uri)
+        .insert_header(("tus-resumable", "1.0.0"))
+        .insert_header(("upload-offset", half.to_string()))
+        .set_payload(file_bytes[half..].to_vec())
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let resp = test::call_service(&app, status()).await;
+    assert_eq!(header(&resp, "upload-offset"), Some(half.to_string()));
+    assert_eq!(test::call_service(&app, test::TestRequest::get().uri(&format!("/files/{}", upload_id)).to_request()).await.status(), 404);
+
+    let resp = test::call_service(&app, patch(half, &file_bytes[half..])).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "upload-offset"), Some(file_bytes.len().to_string()));
+    // Repeating the last PATCH, e.g. after a lost response, is harmless
+    let resp = test::call_service(&app, patch(file_bytes.len(), b"")).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    // The finished upload is a file with the upload's ID
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/files/{}", upload_id)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(test::read_body(resp).await.to_vec(), file_bytes);
+    let meta: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get()
+        .uri(&format!("/files/{}/meta", upload_id))
+        .to_request()).await;
+    assert_eq!(meta["original_filename"], "photo.png");
+    assert_eq!(meta["mime_type"], "image/png");
+    assert_eq!(meta["tags"], serde_json::json!(["beach", "holiday"]));
+    assert_eq!(meta["sha256"], format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(&file_bytes)));
+
+    // Creation with upload: the whole body in the POST, here too long
+    let resp = test::call_service(&app, create()
+        .insert_header(("content-type", "application/offset+octet-stream"))
+        .set_payload([file_bytes.as_slice(), b"extra"].concat())
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = test::call_service(&app, create()
+        .insert_header(("content-type", "application/offset+octet-stream"))
+        .set_payload(file_bytes.clone())
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(header(&resp, "upload-offset"), Some(file_bytes.len().to_string()));
+    let location = header(&resp, "location").unwrap();
+    let meta: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get()
+        .uri(&format!("/files/{}/meta", location.rsplit('/').next().unwrap()))
+        .to_request()).await;
+    assert_eq!(meta["deduplicated"], true);
+
+    // Disallowed content is refused once complete, and the upload removed
+    let exe = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.exe")).unwrap();
+    let resp = test::call_service(&app, test::TestRequest::post()
+        .uri("/uploads")
+        .insert_header(("tus-resumable", "1.0.0"))
+        .insert_header(("upload-length", exe.len().to_string()))
+        .insert_header(("content-type", "application/offset+octet-stream"))
+        .set_payload(exe)
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let conn = db_pool.get().unwrap();
+    let pending: i64 = conn.query_row("SELECT COUNT(*) FROM Upload WHERE completed_at IS NULL", [], |row| row.get(0)).unwrap();
+    assert_eq!(pending, 1, "Only the overlong upload should be left");
+
+    // An upload that can't be stored yet is kept for another try
+    let resp = test::call_service(&app, create().to_request()).await;
+    let location = header(&resp, "location").unwrap();
+    let retry_uri = location[location.find("/uploads/").unwrap()..].to_string();
+    let retry_id = retry_uri.trim_start_matches("/uploads/").to_string();
+    let retry_patch = |offset: usize, body: &[u8]| test::TestRequest::patch()
+        .uri(&retry_uri)
+        .insert_header(("tus-resumable", "1.0.0"))
+        .insert_header(("upload-offset", offset.to_string()))
+        .insert_header(("content-type", "application/offset+octet-stream"))
+        .set_payload(body.to_vec())
+        .to_request();
+    // Storing under a deleted file's ID fails
+    conn.execute("INSERT INTO DeletedFile (uuid) VALUES (?1)", [&retry_id]).unwrap();
+    let resp = test::call_service(&app, retry_patch(0, &file_bytes)).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let resp = test::call_service(&app, test::TestRequest::default()
+        .method(actix_web::http::Method::HEAD)
+        .uri(&retry_uri)
+        .insert_header(("tus-resumable", "1.0.0"))
+        .to_request()).await;
+    assert_eq!(header(&resp, "upload-offset"), Some(file_bytes.len().to_string()));
+    conn.execute("DELETE FROM DeletedFile WHERE uuid = ?1", [&retry_id]).unwrap();
+    let resp = test::call_service(&app, retry_patch(file_bytes.len(), b"")).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/files/{}", retry_id)).to_request()).await;
+    assert_eq!(test::read_body(resp).await.to_vec(), file_bytes);
+    assert!(!media_path.path().join(stowage::storage::upload_key(&retry_id)).exists(), "Upload data is removed once stored");
+
+    // Termination, and expiry
+    let resp = test::call_service(&app, create().to_request()).await;
+    let location = header(&resp, "location").unwrap();
+    let uri = &location[location.find("/uploads/").unwrap()..];
+    let resp = test::call_service(&app, test::TestRequest::delete()
+        .uri(uri)
+        .insert_header(("tus-resumable", "1.0.0"))
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    conn.execute("UPDATE Upload SET expires_at = datetime('now', '-1 seconds')", []).unwrap();
+    let resp = test::call_service(&app, status()).await;
+    assert_eq!(resp.status(), StatusCode::GONE);
+    let resp = test::call_service(&app, status()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/files/{}", upload_id)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Expiry of the upload leaves its file alone");
+}
+
+#[actix_web::test]
+async fn test_resumable_uploads_belong_to_their_key() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    let conn = db_pool.get().unwrap();
+    stowage::db_utils::init_db(&conn).unwrap();
+    let mut state = test_app_state(media_path.path(), &db_pool);
+    state.config.auth.enabled = true;
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(state))
+            .configure(stowage::routes),
+    )
+    .await;
+    use stowage::auth::Scope;
+    let (_, owner) = stowage::auth::mint_key(&conn, "owner", &[Scope::Upload]).unwrap();
+    let (_, other) = stowage::auth::mint_key(&conn, "other", &[Scope::Upload]).unwrap();
+    let (_, admin) = stowage::auth::mint_key(&conn, "admin", &[Scope::Admin]).unwrap();
+    use actix_web::http::Method;
+    let call = |method: Method, uri: &str, key: &str| test::TestRequest::default()
+        .method(method)
+        .uri(uri)
+        .insert_header(("tus-resumable", "1.0.0"))
+        .insert_header(("authorization", format!("Bearer {}", key)));
+
+    let file_bytes = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let resp = test::call_service(&app, call(Method::POST, "/uploads", &owner)
+        .insert_header(("upload-length", file_bytes.len().to_string()))
+        .to_request()).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = resp.headers().get("location").unwrap().to_str().unwrap().to_string();
+    let uri = &location[location.find("/uploads/").unwrap()..];
+    let patch = |key: &str| call(Method::PATCH, uri, key)
+        .insert_header(("upload-offset", "0"))
+        .insert_header(("content-type", "application/offset+octet-stream"))
+        .set_payload(file_bytes.clone())
+        .to_request();
+
+    // Another key can't see, append to or terminate the upload
+    for req in [call(Method::HEAD, uri, &other).to_request(), patch(&other), call(Method::DELETE, uri, &other).to_request()] {
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+    let resp = test::call_service(&app, call(Method::HEAD, uri, &admin).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, patch(&owner)).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(resp.headers().get("upload-offset").unwrap().to_str().unwrap(), file_bytes.len().to_string());
+}
+
+#[actix_web::test]
+async fn test_upload_multiple_files_and_fields() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+
+    let data_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data");
+    let part = |body: &mut Vec<u8>, name: &str, filename: Option<&str>, value: &[u8]| {
+        use std::io::Write;
+        match filename {
+            Some(filename) => write!(body, "--XBOUNDARY\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n", name, filename),
+            None => write!(body, "--XBOUNDARY\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n", name),
+        }.unwrap();
+        body.extend_from_slice(value);
+        body.extend_from_slice(b"\r\n");
+    };
+    let mut body = Vec::new();
+    part(&mut body, "tags", None, b"holiday, beach");
+    part(&mut body, "visibility", None, b"public");
+    part(&mut body, "file", Some("example.png"), &fs::read(data_dir.join("example.png")).unwrap());
+    part(&mut body, "filename", None, b"renamed.json");
+    part(&mut body, "file", Some("example.json"), &fs::read(data_dir.join("example.json")).unwrap());
+    part(&mut body, "file", Some("example.exe"), &fs::read(data_dir.join("example.exe")).unwrap());
+    part(&mut body, "expires_at", None, b"next week");
+    part(&mut body, "file", Some("example.xml"), &fs::read(data_dir.join("example.xml")).unwrap());
+    part(&mut body, "expires_at", None, b"2000-01-01");
+    part(&mut body, "file", Some("example.xml"), &fs::read(data_dir.join("example.xml")).unwrap());
+    part(&mut body, "expires_at", None, b"2999-01-01T12:00:00Z");
+    part(&mut body, "file", Some("example.xml"), &fs::read(data_dir.join("example.xml")).unwrap());
+    body.extend_from_slice(b"--XBOUNDARY--\r\n");
+
+    let req = test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .set_payload(body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let results: serde_json::Value = test::read_body_json(resp).await;
+    let statuses: Vec<u64> = results.as_array().unwrap().iter().map(|r| r["status"].as_u64().unwrap()).collect();
+    assert_eq!(statuses, [201, 201, 400, 400, 400, 201]);
+
+    // Fields apply to every file after them; `filename` only to the next
+    let meta = |file_id: &str| test::TestRequest::get().uri(&format!("/files/{}/meta", file_id)).to_request();
+    let png: serde_json::Value = test::call_and_read_body_json(&app, meta(results[0]["file_id"].as_str().unwrap())).await;
+    assert_eq!(png["original_filename"], "example.png");
+    assert_eq!(png["tags"], serde_json::json!(["beach", "holiday"]));
+    assert_eq!(png["public"], true);
+    assert!(png["expires_at"].is_null());
+    let json: serde_json::Value = test::call_and_read_body_json(&app, meta(results[1]["file_id"].as_str().unwrap())).await;
+    assert_eq!(json["original_filename"], "renamed.json");
+    assert_eq!(json["tags"], serde_json::json!(["beach", "holiday"]));
+
+    // Each file succeeds or fails on its own, and an invalid field fails
+    // the files after it until it is set again
+    assert_eq!(results[2]["filename"], "example.exe");
+    assert!(results[2]["file_id"].is_null());
+    assert!(results[2]["error"].is_string());
+    assert!(results[3]["error"].as_str().unwrap().contains("expires_at"));
+    assert!(results[1]["error"].is_null());
+    // A time that has passed would hide the file as soon as it was stored
+    assert!(results[4]["error"].as_str().unwrap().contains("not in the future"));
+    let expiring = results[5]["file_id"].as_str().unwrap();
+    let xml: serde_json::Value = test::call_and_read_body_json(&app, meta(expiring)).await;
+    assert_eq!(xml["expires_at"], "2999-01-01 12:00:00");
+
+    // The expiry can be changed later, under the same rule
+    let update = |expires_at: &str| test::TestRequest::patch()
+        .uri(&format!("/files/{}", expiring))
+        .set_json(serde_json::json!({"expires_at": expires_at}))
+        .to_request();
+    let resp = test::call_service(&app, update("2000-01-01")).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let xml: serde_json::Value = test::call_and_read_body_json(&app, update("")).await;
+    assert!(xml["expires_at"].is_null());
+    let xml: serde_json::Value = test::call_and_read_body_json(&app, update("2999-06-01")).await;
+    assert_eq!(xml["expires_at"], "2999-06-01 00:00:00");
+
+    // A file past its expiry is gone
+    let conn = db_pool.get().unwrap();
+    conn.execute("UPDATE File SET expires_at = datetime('now', '-1 seconds') WHERE uuid = ?1", [expiring]).unwrap();
+    let resp = test::call_service(&app, meta(expiring)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(stowage::db_utils::expired_files(&conn).unwrap(), [expiring]);
+    let resp = test::call_service(&app, update("2999-06-01")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND, "An expired file can't be revived");
+
+    let mut body = Vec::new();
+    part(&mut body, "tags", None, b"lonely");
+    body.extend_from_slice(b"--XBOUNDARY--\r\n");
+    let req = test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .set_payload(body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "Fields without a file");
+}
+
+#[actix_web::test]
+async fn test_put_raw_body() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+    let data_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data");
+    let png = fs::read(data_dir.join("example.png")).unwrap();
+    let json = fs::read(data_dir.join("example.json")).unwrap();
+    let meta = |file_id: &str| test::TestRequest::get().uri(&format!("/files/{}/meta", file_id)).to_request();
+
+    // JSON can't be sniffed, so the Content-Type supplies the extension
+    let req = test::TestRequest::put()
+        .uri("/files?tags=raw")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(json.clone())
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let file: serde_json::Value = test::call_and_read_body_json(&app, meta(body["file_id"].as_str().unwrap())).await;
+    assert_eq!(file["original_filename"], "file.json");
+    assert_eq!(file["mime_type"], "application/json");
+    assert_eq!(file["tags"], serde_json::json!(["raw"]));
+
+    let req = test::TestRequest::put()
+        .uri("/files")
+        .insert_header(("content-disposition", "attachment; filename=\"cover.png\""))
+        .insert_header(("x-filename", "ignored.png"))
+        .set_payload(png.clone())
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let file: serde_json::Value = test::call_and_read_body_json(&app, meta(body["file_id"].as_str().unwrap())).await;
+    assert_eq!(file["original_filename"], "cover.png");
+    assert_eq!(file["size"], png.len());
+
+    let req = test::TestRequest::put()
+        .uri("/files")
+        .insert_header(("x-filename", "example.exe"))
+        .set_payload(fs::read(data_dir.join("example.exe")).unwrap())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+    // With a client ID, repeating the request is harmless
+    let client_id = "6f1c2b9e-3d4a-4e8b-9c7d-1a2b3c4d5e6f";
+    let put = |body: Vec<u8>, filename: &str| test::TestRequest::put()
+        .uri(&format!("/files/{}", client_id))
+        .insert_header(("x-filename", filename))
+        .set_payload(body)
+        .to_request();
+    let resp = test::call_service(&app, put(png.clone(), "photo.png")).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["file_id"], client_id);
+    assert_eq!(body["deduplicated"], true);
+    let resp = test::call_service(&app, put(png.clone(), "photo.png")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["file_id"], client_id);
+    let resp = test::call_service(&app, put(json.clone(), "photo.json")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/files/{}", client_id)).to_request()).await;
+    assert_eq!(test::read_body(resp).await, png);
+
+    let req = test::TestRequest::put().uri("/files/not-a-uuid").set_payload(png).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    let conn = db_pool.get().unwrap();
+    let files: i64 = conn.query_row("SELECT COUNT(*) FROM File", [], |row| row.get(0)).unwrap();
+    assert_eq!(files, 3);
+    drop(conn);
+
+    // A deleted ID can't be given new content, or even the same content again
+    let req = test::TestRequest::delete().uri(&format!("/files/{}", client_id)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let resp = test::call_service(&app, put(json, "photo.json")).await;
+    assert_eq!(resp.status(), StatusCode::GONE);
+    let resp = test::call_service(&app, put(fs::read(data_dir.join("example.png")).unwrap(), "photo.png")).await;
+    assert_eq!(resp.status(), StatusCode::GONE);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/files/{}", client_id)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let conn = db_pool.get().unwrap();
+    assert!(stowage::db_utils::is_file_deleted(&conn, client_id).unwrap());
+    // Inserting directly is refused too
+    let inserted = conn.execute(
+        "INSERT INTO File (uuid, filepath, url, hash, blob_id) SELECT ?1, filepath, url, hash, blob_id FROM File LIMIT 1",
+        [client_id],
+    );
+    assert!(inserted.unwrap_err().to_string().contains("File ID was deleted"));
+}
+
+#[actix_web::test]
+async fn test_upload_checksums() {
+    use base64::Engine;
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+    let png = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let sha256 = Sha256::digest(&png);
+    let sha256_b64 = base64::engine::general_purpose::STANDARD.encode(sha256);
+    let put = |name: &str, value: String| test::TestRequest::put()
+        .uri("/files")
+        .insert_header(("x-filename", "example.png"))
+        .insert_header((name.to_string(), value))
+        .set_payload(png.clone())
+        .to_request();
+
+    let resp = test::call_service(&app, put("content-md5", "1B2M2Y8AsgTpgAmY7PhCfg==".into())).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY, "MD5 of the empty string");
+    let resp = test::call_service(&app, put("x-checksum-sha256", "not a checksum".into())).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let tmp_dir = media_path.path().join("tmp");
+    assert!(!tmp_dir.exists() || fs::read_dir(&tmp_dir).unwrap().next().is_none(), "Rejected uploads are removed");
+
+    let resp = test::call_service(&app, put("repr-digest", format!("sha-256=:{}:", sha256_b64))).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = test::call_service(&app, put("x-checksum-sha256", format!("{:x}", sha256))).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+
+    // Downloads carry the digest of the whole file, even for a range
+    let req = test::TestRequest::get()
+        .uri(&format!("/files/{}", body["file_id"].as_str().unwrap()))
+        .insert_header(("range", "bytes=0-9"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.headers().get("repr-digest").unwrap(), &format!("sha-256=:{}:", sha256_b64));
+    assert_eq!(resp.headers().get("digest").unwrap(), &format!("sha-256={}", sha256_b64));
+
+    // In a multipart upload each part carries its own checksum
+    let mut body = Vec::new();
+    for digest in [sha256_b64.clone(), base64::engine::general_purpose::STANDARD.encode([0u8; 32])] {
+        body.extend_from_slice(format!(
+            "--XBOUNDARY\r\nContent-Disposition: form-data; name=\"file\"; filename=\"example.png\"\r\nDigest: sha-256={}\r\n\r\n",
+            digest
+        ).as_bytes());
+        body.extend_from_slice(&png);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(b"--XBOUNDARY--\r\n");
+    let req = test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", "multipart/form-data; boundary=XBOUNDARY"))
+        .set_payload(body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let results: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(results[0]["status"], 201);
+    assert_eq!(results[1]["status"], 422);
+}
+
+#[actix_web::test]
+async fn test_concurrent_identical_uploads_share_one_blob() {
+    let media_path = tempfile::tempdir().unwrap();
+    let db_file = tempfile::NamedTempFile::new().unwrap();
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(db_file.path());
+    let db_pool = r2d2::Pool::new(manager).unwrap();
+    stowage::db_utils::init_db(&db_pool.get().unwrap()).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(actix_web::web::Data::new(test_app_state(media_path.path(), &db_pool)))
+            .configure(stowage::routes),
+    )
+    .await;
+    let png = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".data/example.png")).unwrap();
+    let put = || test::TestRequest::put()
+        .uri("/files")
+        .insert_header(("x-filename", "example.png"))
+        .set_payload(png.clone())
+        .to_request();
+
+    let (first, second) = futures::join!(test::call_service(&app, put()), test::call_service(&app, put()));
+    assert_eq!((first.status(), second.status()), (StatusCode::CREATED, StatusCode::CREATED));
+    let first: serde_json::Value = test::read_body_json(first).await;
+    let second: serde_json::Value = test::read_body_json(second).await;
+    assert_ne!(first["deduplicated"], second["deduplicated"], "Exactly one upload stores the content");
+
+    // Only the winner's object was moved into place; the loser's is gone
+    let conn = db_pool.get().unwrap();
+    let (blobs, refs): (i64, i64) = conn.query_row("SELECT COUNT(*), SUM(ref_count) FROM Blob", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    assert_eq!((blobs, refs), (1, 2));
+    let mut objects = 0;
+    let mut dirs = vec![media_path.path().to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() { dirs.push(path) } else { objects += 1 }
+        }
+    }
+    assert_eq!(objects, 1);
+    for file in [&first, &second] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(file["download_url"].as_str().unwrap()).to_request()).await;
+        assert_eq!(test::read_body(resp).await, png);
+    }
+
+    // Databases from before blobs were unique get their duplicates merged
+    conn.execute_batch("DROP INDEX idx_blob_hash_unique").unwrap();
+    let blob_id: i64 = conn.query_row("SELECT blob_id FROM File WHERE uuid = ?1", [first["file_id"].as_str().unwrap()], |row| row.get(0)).unwrap();
+    conn.execute("INSERT INTO Blob (hash, filepath, size, ref_count) SELECT hash, 'du/pl/duplicate.png', size, 1 FROM Blob WHERE id = ?1", [blob_id]).unwrap();
+    conn.execute("UPDATE File SET blob_id = last_insert_rowid(), filepath = 'du/pl/duplicate.png' WHERE uuid = ?1", [second["file_id"].as_str().unwrap()]).unwrap();
+    conn.execute("UPDATE Blob SET ref_count = 1 WHERE id = ?1", [blob_id]).unwrap();
+    stowage::db_utils::init_db(&conn).unwrap();
+    let (blobs, refs): (i64, i64) = conn.query_row("SELECT COUNT(*), SUM(ref_count) FROM Blob", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    assert_eq!((blobs, refs), (1, 2));
+    let moved = stowage::db_utils::get_file_by_uuid(&conn, second["file_id"].as_str().unwrap()).unwrap().unwrap();
+    assert_eq!(moved.blob_id, blob_id);
+}
+
 // #[actix_web::test]
 // async fn test_download_and_poll_until_complete() {
 //     init_test_logger();
@@ -441,7 +1808,7 @@ async fn test_download_job_creation() {
 //     // 1. Make a request to create a download job
 //     let req = test::TestRequest::post()
 //         .uri("/download")
-//         .set_json(&json!({ "download_url": test_url }))
+//         .set_json(json!({ "download_url": test_url }))
 //         .to_request();
 //     let resp = test::call_service(&app, req).await;
     